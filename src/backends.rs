@@ -0,0 +1,129 @@
+pub mod acrcloud;
+pub mod audd;
+
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::config::{BackendKind, Config};
+#[cfg(feature = "local-db")]
+use crate::shazam::core::cache::TrackCache;
+use crate::shazam::core::http::try_recognize_song;
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+use crate::shazam::error::RecognitionError;
+use crate::shazam::fingerprinting::communication::Recognizer;
+use crate::shazam::fingerprinting::signature_format::DecodedSignature;
+
+use acrcloud::AcrCloud;
+use audd::AudD;
+
+/// A song recogniser. Shazam only needs the signature, the others get the raw audio.
+pub enum Backend {
+    Shazam(Box<Recognizer>),
+    AudD(AudD),
+    AcrCloud(AcrCloud),
+}
+
+impl Backend {
+    pub fn from_config(config: &Config, recognizer: &Recognizer) -> Result<Vec<Backend>, String> {
+        config.backends.iter().map(|kind| match kind {
+            BackendKind::Shazam => Ok(Backend::Shazam(Box::new(recognizer.clone()))),
+            BackendKind::Audd => match &config.audd {
+                Some(audd) => Ok(Backend::AudD(AudD::new(audd, recognizer.client()))),
+                None => Err("The audd backend needs an \"audd\" section in the config".to_string()),
+            },
+            BackendKind::Acrcloud => match &config.acrcloud {
+                Some(acrcloud) => Ok(Backend::AcrCloud(AcrCloud::new(acrcloud, recognizer.client()))),
+                None => Err("The acrcloud backend needs an \"acrcloud\" section in the config".to_string()),
+            },
+        }).collect()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Shazam(_) => "SHAZAM",
+            Backend::AudD(_) => "AUDD",
+            Backend::AcrCloud(_) => "ACRCLOUD",
+        }
+    }
+
+    /// Whether it needs the audio itself rather than just the signature.
+    pub fn needs_audio(&self) -> bool {
+        !matches!(self, Backend::Shazam(_))
+    }
+
+    /// `s16_mono_16khz_buffer` is the audio `signature` was made from.
+    pub async fn recognize(&self, s16_mono_16khz_buffer: &[i16], signature: &DecodedSignature, timestamp: SystemTime) -> Result<SongRecognizedMessage, RecognitionError> {
+        match self {
+            Backend::Shazam(recognizer) => try_recognize_song(recognizer, signature.clone(), timestamp).await,
+            Backend::AudD(audd) => audd.recognize(s16_mono_16khz_buffer, signature, timestamp).await,
+            Backend::AcrCloud(acrcloud) => acrcloud.recognize(s16_mono_16khz_buffer, signature, timestamp).await,
+        }
+    }
+}
+
+/// Tries each backend in turn until one finds the song, skipping the network
+/// entirely when the signature clearly continues the last match. See
+/// [`try_backends`] for `on_failure`.
+#[cfg(feature = "local-db")]
+pub async fn try_recognize_song_cached(backends: &[Backend], cache: &mut TrackCache, s16_mono_16khz_buffer: &[i16], signature: DecodedSignature, timestamp: SystemTime, on_failure: impl FnMut(&Backend, &RecognitionError)) -> Result<SongRecognizedMessage, RecognitionError> {
+    let signature = match cache.extrapolate(signature, timestamp) {
+        Ok(song) => return Ok(song),
+        Err(signature) => signature,
+    };
+
+    let mut song = try_backends(backends, s16_mono_16khz_buffer, &signature, timestamp, on_failure).await?;
+    cache.merge(&mut song);
+    Ok(song)
+}
+
+/// Tries each backend in turn until one finds the song. The ones that need
+/// audio are skipped if there isn't any, e.g. for a signature queued without
+/// it. If they all fail, the first error is returned. With more than one
+/// backend, `on_failure` hears about each one that fails on the way.
+pub async fn try_backends(backends: &[Backend], s16_mono_16khz_buffer: &[i16], signature: &DecodedSignature, timestamp: SystemTime, mut on_failure: impl FnMut(&Backend, &RecognitionError)) -> Result<SongRecognizedMessage, RecognitionError> {
+    let mut first_error = None;
+
+    for backend in backends {
+        if backend.needs_audio() && s16_mono_16khz_buffer.is_empty() {
+            continue;
+        }
+
+        match backend.recognize(s16_mono_16khz_buffer, signature, timestamp).await {
+            Ok(song) => return Ok(song),
+            Err(e) => {
+                if backends.len() > 1 {
+                    on_failure(backend, &e);
+                }
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    Err(first_error.unwrap_or(RecognitionError::NoMatch { retry_after: None }))
+}
+
+/// Wraps 16 KHz mono PCM into a WAV file for the backends that want audio files.
+fn encode_wav(s16_mono_16khz_buffer: &[i16]) -> Vec<u8> {
+    let data_size = s16_mono_16khz_buffer.len() as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+
+    wav.extend_from_slice(b"RIFF");
+    wav.write_u32::<LittleEndian>(36 + data_size).unwrap();
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.write_u32::<LittleEndian>(16).unwrap(); // fmt chunk size
+    wav.write_u16::<LittleEndian>(1).unwrap(); // PCM
+    wav.write_u16::<LittleEndian>(1).unwrap(); // channels
+    wav.write_u32::<LittleEndian>(16_000).unwrap(); // sample rate
+    wav.write_u32::<LittleEndian>(16_000 * 2).unwrap(); // byte rate
+    wav.write_u16::<LittleEndian>(2).unwrap(); // block align
+    wav.write_u16::<LittleEndian>(16).unwrap(); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.write_u32::<LittleEndian>(data_size).unwrap();
+
+    for sample in s16_mono_16khz_buffer {
+        wav.write_i16::<LittleEndian>(*sample).unwrap();
+    }
+
+    wav
+}
@@ -0,0 +1,310 @@
+use std::time::{Duration, SystemTime};
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use reqwest::multipart::{Form, Part};
+use reqwest::Client;
+use serde::Deserialize;
+use sha1::Sha1;
+
+use crate::config::AcrCloudConfig;
+use crate::shazam::core::thread_messages::{SongRecognizedMessage, TrackLinks};
+use crate::shazam::error::RecognitionError;
+use crate::shazam::fingerprinting::signature_format::DecodedSignature;
+
+use super::encode_wav;
+
+/// https://docs.acrcloud.com/reference/identification-api
+pub struct AcrCloud {
+    client: Client,
+    base_url: String,
+    access_key: String,
+    access_secret: String,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudResponse {
+    status: AcrCloudStatus,
+    metadata: Option<AcrCloudMetadata>,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudStatus {
+    code: i64,
+    msg: String,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudMetadata {
+    #[serde(default)]
+    music: Vec<AcrCloudMusic>,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudMusic {
+    acrid: String,
+    title: String,
+    #[serde(default)]
+    artists: Vec<AcrCloudName>,
+    album: Option<AcrCloudName>,
+    #[serde(default)]
+    genres: Vec<AcrCloudName>,
+    label: Option<String>,
+    release_date: Option<String>,
+    /// Position in the track at the end of the sample.
+    play_offset_ms: Option<u64>,
+    duration_ms: Option<u64>,
+    external_ids: Option<AcrCloudExternalIds>,
+    external_metadata: Option<AcrCloudExternalMetadata>,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudName {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudExternalIds {
+    isrc: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudExternalMetadata {
+    spotify: Option<AcrCloudSpotify>,
+    youtube: Option<AcrCloudYouTube>,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudSpotify {
+    track: Option<AcrCloudId>,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudId {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct AcrCloudYouTube {
+    vid: String,
+}
+
+const NO_RESULT: i64 = 1001;
+const LIMIT_EXCEEDED: i64 = 3003;
+
+impl AcrCloud {
+    pub fn new(config: &AcrCloudConfig, client: Client) -> AcrCloud {
+        AcrCloud {
+            client,
+            base_url: config.base_url.clone()
+                .unwrap_or_else(|| format!("https://{}", config.host))
+                .trim_end_matches('/')
+                .to_string(),
+            access_key: config.access_key.clone(),
+            access_secret: config.access_secret.clone(),
+        }
+    }
+
+    /// HMAC-SHA1 of the method, path, key, data type, signature version and
+    /// timestamp, one per line, keyed with the secret.
+    fn signature(&self, request_timestamp: &str) -> String {
+        let string_to_sign = format!("POST\n/v1/identify\n{}\naudio\n1\n{}", self.access_key, request_timestamp);
+        let mut mac = Hmac::<Sha1>::new_from_slice(self.access_secret.as_bytes()).unwrap();
+        mac.update(string_to_sign.as_bytes());
+        BASE64_STANDARD.encode(mac.finalize().into_bytes())
+    }
+
+    pub async fn recognize(&self, s16_mono_16khz_buffer: &[i16], signature: &DecodedSignature, timestamp: SystemTime) -> Result<SongRecognizedMessage, RecognitionError> {
+        let wav = encode_wav(s16_mono_16khz_buffer);
+        let request_timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs().to_string();
+
+        let form = Form::new()
+            .text("access_key", self.access_key.clone())
+            .text("data_type", "audio")
+            .text("signature_version", "1")
+            .text("signature", self.signature(&request_timestamp))
+            .text("sample_bytes", wav.len().to_string())
+            .text("timestamp", request_timestamp)
+            .part("sample", Part::bytes(wav).file_name("sample.wav").mime_str("audio/wav")?);
+
+        let response = self.client.post(format!("{}/v1/identify", self.base_url))
+            .multipart(form)
+            .send().await?;
+
+        if !response.status().is_success() {
+            return Err(RecognitionError::from_status(&response));
+        }
+
+        let response: AcrCloudResponse = serde_json::from_value(response.json().await?)?;
+
+        match response.status.code {
+            0 => {}
+            NO_RESULT => return Err(RecognitionError::NoMatch { retry_after: None }),
+            LIMIT_EXCEEDED => return Err(RecognitionError::Throttled { retry_after: None }),
+            code => return Err(RecognitionError::Api { code, message: response.status.msg }),
+        }
+
+        let music = response.metadata
+            .and_then(|metadata| metadata.music.into_iter().next())
+            .ok_or(RecognitionError::NoMatch { retry_after: None })?;
+
+        let sample_length = s16_mono_16khz_buffer.len() as f32 / 16_000.0;
+        let external_metadata = music.external_metadata.as_ref();
+
+        Ok(SongRecognizedMessage {
+            artist_name: music.artists.iter().map(|artist| artist.name.as_str()).collect::<Vec<_>>().join(", "),
+            album_name: music.album.map(|album| album.name),
+            song_name: music.title,
+            cover_image: None,
+            local_cover: None,
+            track_seek: music.play_offset_ms.map(|offset| (offset as f32 / 1000.0 - sample_length).max(0.0)),
+            matches: vec![],
+            signature: Box::new(signature.clone()),
+            track_key: format!("acrcloud:{}", music.acrid),
+            release_year: music.release_date,
+            genre: music.genres.into_iter().next().map(|genre| genre.name),
+            isrc: music.external_ids.and_then(|ids| ids.isrc),
+            label: music.label,
+            share_url: None,
+            links: TrackLinks {
+                apple_music: None,
+                spotify: external_metadata
+                    .and_then(|metadata| metadata.spotify.as_ref())
+                    .and_then(|spotify| spotify.track.as_ref())
+                    .map(|track| format!("https://open.spotify.com/track/{}", track.id)),
+                youtube_music: external_metadata
+                    .and_then(|metadata| metadata.youtube.as_ref())
+                    .map(|youtube| format!("https://music.youtube.com/watch?v={}", youtube.vid)),
+            },
+            album_id: None,
+            lyrics: None,
+            musicbrainz: None,
+            duration: music.duration_ms.map(Duration::from_millis),
+            shazam_json: String::new(),
+            timestamp,
+            extrapolated: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shazam::fingerprinting::algorithm::SignatureGenerator;
+    use crate::testing::{field, song, Request, Stub, SONG_A};
+
+    fn acrcloud(base_url: Option<String>) -> AcrCloud {
+        let config = AcrCloudConfig {
+            host: "identify-eu-west-1.acrcloud.com".to_string(),
+            access_key: "test-key".to_string(),
+            access_secret: "test-secret".to_string(),
+            base_url,
+        };
+        AcrCloud::new(&config, Client::new())
+    }
+
+    fn response(code: i64, play_offset_ms: u64) -> String {
+        serde_json::json!({
+            "status": {"code": code, "msg": "Success", "version": "1.0"},
+            "metadata": {
+                "timestamp_utc": "2024-05-07 16:42:43",
+                "music": [{
+                    "acrid": "6049f11da7095e8bb8266871d4a70873",
+                    "title": "Paper Lanterns",
+                    "artists": [{"name": "The Quiet Harbour"}, {"name": "Mira Calloway"}],
+                    "album": {"name": "Low Tide Letters"},
+                    "genres": [{"name": "Alternative"}],
+                    "label": "Driftwood Records",
+                    "release_date": "2021-09-03",
+                    "play_offset_ms": play_offset_ms,
+                    "duration_ms": 202000,
+                    "score": 100,
+                    "external_ids": {"isrc": "GBXYZ2100017"},
+                    "external_metadata": {
+                        "spotify": {"track": {"id": "4uLU6hMCjMI75M1A2tKUQC"}},
+                        "youtube": {"vid": "dQw4w9WgXcQ"}
+                    }
+                }]
+            },
+            "result_type": 0
+        })
+        .to_string()
+    }
+
+    async fn recognize(stub: &Stub) -> Result<SongRecognizedMessage, RecognitionError> {
+        let audio = song(SONG_A, 0, 3, 1);
+        acrcloud(Some(stub.url.clone()))
+            .recognize(&audio, &SignatureGenerator::make_signature_from_buffer(&audio), SystemTime::now())
+            .await
+    }
+
+    fn respond_with(body: String) -> impl Fn(&Request) -> (u16, String) {
+        move |_| (200, body.clone())
+    }
+
+    #[test]
+    fn signs_requests() {
+        // Worked out separately with Python's hmac module
+        assert_eq!(acrcloud(None).signature("1715100163"), "2hHdqqooevzbfzpO0RAZNTNQhcA=");
+        assert_eq!(acrcloud(None).base_url, "https://identify-eu-west-1.acrcloud.com");
+    }
+
+    #[tokio::test]
+    async fn sends_a_signed_sample() {
+        let stub = Stub::start(respond_with(response(0, 75_000))).await;
+        recognize(&stub).await.unwrap();
+
+        let requests = stub.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].target, "/v1/identify");
+
+        let parts = requests[0].multipart();
+        assert_eq!(field(&parts, "access_key"), "test-key");
+        assert_eq!(field(&parts, "data_type"), "audio");
+        assert_eq!(field(&parts, "signature_version"), "1");
+        assert_eq!(field(&parts, "signature"), acrcloud(None).signature(field(&parts, "timestamp")));
+        assert_eq!(field(&parts, "sample_bytes"), (44 + 3 * 16_000 * 2).to_string());
+        let sample = parts.iter().find(|part| part.name == "sample").unwrap();
+        assert_eq!(sample.file_name.as_deref(), Some("sample.wav"));
+        assert_eq!(sample.content_type.as_deref(), Some("audio/wav"));
+        assert!(sample.data.starts_with(b"RIFF"));
+        assert_eq!(sample.data.len().to_string(), field(&parts, "sample_bytes"));
+    }
+
+    #[tokio::test]
+    async fn parses_a_match() {
+        let stub = Stub::start(respond_with(response(0, 75_000))).await;
+        let song = recognize(&stub).await.unwrap();
+
+        assert_eq!(song.song_name, "Paper Lanterns");
+        assert_eq!(song.artist_name, "The Quiet Harbour, Mira Calloway");
+        assert_eq!(song.track_key, "acrcloud:6049f11da7095e8bb8266871d4a70873");
+        assert_eq!(song.isrc.as_deref(), Some("GBXYZ2100017"));
+        assert_eq!(song.duration, Some(Duration::from_secs(202)));
+        assert_eq!(song.links.spotify.as_deref(), Some("https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC"));
+        assert_eq!(song.links.youtube_music.as_deref(), Some("https://music.youtube.com/watch?v=dQw4w9WgXcQ"));
+    }
+
+    #[tokio::test]
+    async fn seeks_to_the_start_of_the_sample() {
+        // The offset is where the 3 second sample ends
+        let stub = Stub::start(respond_with(response(0, 75_000))).await;
+        assert_eq!(recognize(&stub).await.unwrap().track_seek, Some(72.0));
+
+        let stub = Stub::start(respond_with(response(0, 1_500))).await;
+        assert_eq!(recognize(&stub).await.unwrap().track_seek, Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn maps_errors() {
+        let stub = Stub::start(respond_with(response(NO_RESULT, 0))).await;
+        assert!(matches!(recognize(&stub).await, Err(RecognitionError::NoMatch { .. })));
+
+        let stub = Stub::start(respond_with(response(LIMIT_EXCEEDED, 0))).await;
+        assert!(matches!(recognize(&stub).await, Err(RecognitionError::Throttled { .. })));
+
+        let stub = Stub::start(respond_with(response(3001, 0))).await;
+        assert!(matches!(recognize(&stub).await, Err(RecognitionError::Api { code: 3001, .. })));
+    }
+}
@@ -0,0 +1,282 @@
+use std::time::SystemTime;
+
+use reqwest::multipart::{Form, Part};
+use reqwest::Client;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::config::AudDConfig;
+use crate::shazam::core::thread_messages::{SongRecognizedMessage, TrackLinks};
+use crate::shazam::error::RecognitionError;
+use crate::shazam::fingerprinting::signature_format::DecodedSignature;
+
+use super::encode_wav;
+
+/// https://docs.audd.io/
+pub struct AudD {
+    client: Client,
+    api_token: String,
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+struct AudDResponse {
+    status: String,
+    result: Option<AudDResult>,
+    error: Option<AudDError>,
+}
+
+#[derive(Deserialize)]
+struct AudDError {
+    error_code: i64,
+    error_message: String,
+}
+
+#[derive(Deserialize)]
+struct AudDResult {
+    artist: String,
+    title: String,
+    album: Option<String>,
+    release_date: Option<String>,
+    label: Option<String>,
+    /// "MM:SS"
+    timecode: Option<String>,
+    song_link: Option<String>,
+    apple_music: Option<AudDAppleMusic>,
+    spotify: Option<AudDSpotify>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AudDAppleMusic {
+    url: Option<String>,
+    isrc: Option<String>,
+    #[serde(default)]
+    genre_names: Vec<String>,
+    artwork: Option<AudDArtwork>,
+}
+
+#[derive(Deserialize)]
+struct AudDArtwork {
+    /// With `{w}` and `{h}` placeholders.
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct AudDSpotify {
+    external_urls: Option<AudDSpotifyUrls>,
+}
+
+#[derive(Deserialize)]
+struct AudDSpotifyUrls {
+    spotify: Option<String>,
+}
+
+// Error codes for exhausted request limits
+const LIMIT_REACHED: [i64; 2] = [901, 902];
+
+impl AudD {
+    pub fn new(config: &AudDConfig, client: Client) -> AudD {
+        AudD {
+            client,
+            api_token: config.api_token.clone(),
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub async fn recognize(&self, s16_mono_16khz_buffer: &[i16], signature: &DecodedSignature, timestamp: SystemTime) -> Result<SongRecognizedMessage, RecognitionError> {
+        let file = Part::bytes(encode_wav(s16_mono_16khz_buffer))
+            .file_name("sample.wav")
+            .mime_str("audio/wav")?;
+
+        let form = Form::new()
+            .text("api_token", self.api_token.clone())
+            .text("return", "apple_music,spotify")
+            .part("file", file);
+
+        let response = self.client.post(format!("{}/", self.base_url))
+            .multipart(form)
+            .send().await?;
+
+        if !response.status().is_success() {
+            return Err(RecognitionError::from_status(&response));
+        }
+
+        let response: AudDResponse = serde_json::from_value(response.json().await?)?;
+
+        if response.status != "success" {
+            return Err(match response.error {
+                Some(error) if LIMIT_REACHED.contains(&error.error_code) => RecognitionError::Throttled { retry_after: None },
+                Some(error) => RecognitionError::Api { code: error.error_code, message: error.error_message },
+                None => RecognitionError::MalformedResponse(format!("Unexpected status {}", response.status).into()),
+            });
+        }
+
+        let result = response.result.ok_or(RecognitionError::NoMatch { retry_after: None })?;
+
+        let track_key = track_key(&result);
+        let apple_music = result.apple_music.as_ref();
+
+        Ok(SongRecognizedMessage {
+            artist_name: result.artist,
+            album_name: result.album,
+            song_name: result.title,
+            cover_image: apple_music
+                .and_then(|apple_music| apple_music.artwork.as_ref())
+                .map(|artwork| artwork.url.replace("{w}", "400").replace("{h}", "400")),
+            local_cover: None,
+            track_seek: result.timecode.as_deref().and_then(parse_timecode),
+            matches: vec![],
+            signature: Box::new(signature.clone()),
+            track_key,
+            release_year: result.release_date,
+            genre: apple_music.and_then(|apple_music| apple_music.genre_names.first().cloned()),
+            isrc: apple_music.and_then(|apple_music| apple_music.isrc.clone()),
+            label: result.label,
+            share_url: result.song_link,
+            links: TrackLinks {
+                apple_music: apple_music.and_then(|apple_music| apple_music.url.clone()),
+                spotify: result.spotify
+                    .and_then(|spotify| spotify.external_urls)
+                    .and_then(|urls| urls.spotify),
+                youtube_music: None,
+            },
+            album_id: None,
+            lyrics: None,
+            musicbrainz: None,
+            duration: None,
+            shazam_json: String::new(),
+            timestamp,
+            extrapolated: false,
+        })
+    }
+}
+
+/// AudD has no IDs of its own. The song.link URL is the closest thing, but it's
+/// not always there, so fall back to the ISRC and then to the artist and title.
+fn track_key(result: &AudDResult) -> String {
+    if let Some(song_link) = &result.song_link {
+        return format!("audd:{}", song_link);
+    }
+    if let Some(isrc) = result.apple_music.as_ref().and_then(|apple_music| apple_music.isrc.as_ref()) {
+        return format!("audd:isrc:{}", isrc);
+    }
+
+    let mut hash = Sha1::new();
+    hash.update(result.artist.to_lowercase());
+    hash.update("\n");
+    hash.update(result.title.to_lowercase());
+    let hash: String = hash.finalize().iter().take(8).map(|byte| format!("{:02x}", byte)).collect();
+    format!("audd:{}", hash)
+}
+
+fn parse_timecode(timecode: &str) -> Option<f32> {
+    let (minutes, seconds) = timecode.split_once(':')?;
+    Some(minutes.parse::<f32>().ok()? * 60.0 + seconds.parse::<f32>().ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shazam::fingerprinting::algorithm::SignatureGenerator;
+    use crate::testing::{field, song, Stub, SONG_A};
+
+    const MATCH: &str = r#"{
+        "status": "success",
+        "result": {
+            "artist": "The Quiet Harbour",
+            "title": "Paper Lanterns",
+            "album": "Low Tide Letters",
+            "release_date": "2021-09-03",
+            "label": "Driftwood Records",
+            "timecode": "01:15",
+            "song_link": "https://lis.tn/PaperLanterns",
+            "apple_music": {
+                "url": "https://music.apple.com/us/album/paper-lanterns/1589473001?i=1589473220",
+                "isrc": "GBXYZ2100017",
+                "genreNames": ["Alternative", "Music"],
+                "artwork": {"url": "https://is1-ssl.mzstatic.com/image/thumb/Music/v4/11/22/33/cover.jpg/{w}x{h}bb.jpg"}
+            },
+            "spotify": {"external_urls": {"spotify": "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC"}}
+        }
+    }"#;
+
+    async fn recognize(stub: &Stub) -> Result<SongRecognizedMessage, RecognitionError> {
+        let audd = AudD::new(&AudDConfig { api_token: "test-token".to_string(), base_url: format!("{}/", stub.url) }, Client::new());
+        let audio = song(SONG_A, 0, 3, 1);
+        audd.recognize(&audio, &SignatureGenerator::make_signature_from_buffer(&audio), SystemTime::now()).await
+    }
+
+    fn respond_with(body: &str) -> impl Fn(&crate::testing::Request) -> (u16, String) {
+        let body = body.to_string();
+        move |_| (200, body.clone())
+    }
+
+    #[tokio::test]
+    async fn sends_the_audio_as_a_wav_file() {
+        let stub = Stub::start(respond_with(MATCH)).await;
+        recognize(&stub).await.unwrap();
+
+        let requests = stub.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].target, "/");
+
+        let parts = requests[0].multipart();
+        assert_eq!(field(&parts, "api_token"), "test-token");
+        assert_eq!(field(&parts, "return"), "apple_music,spotify");
+        let file = parts.iter().find(|part| part.name == "file").unwrap();
+        assert_eq!(file.file_name.as_deref(), Some("sample.wav"));
+        assert_eq!(file.content_type.as_deref(), Some("audio/wav"));
+        assert!(file.data.starts_with(b"RIFF"));
+        assert_eq!(file.data.len(), 44 + 3 * 16_000 * 2);
+    }
+
+    #[tokio::test]
+    async fn parses_a_match() {
+        let stub = Stub::start(respond_with(MATCH)).await;
+        let song = recognize(&stub).await.unwrap();
+
+        assert_eq!(song.song_name, "Paper Lanterns");
+        assert_eq!(song.artist_name, "The Quiet Harbour");
+        assert_eq!(song.track_key, "audd:https://lis.tn/PaperLanterns");
+        assert_eq!(song.track_seek, Some(75.0));
+        assert_eq!(song.isrc.as_deref(), Some("GBXYZ2100017"));
+        assert_eq!(song.genre.as_deref(), Some("Alternative"));
+        assert_eq!(song.cover_image.as_deref(), Some("https://is1-ssl.mzstatic.com/image/thumb/Music/v4/11/22/33/cover.jpg/400x400bb.jpg"));
+        assert_eq!(song.links.spotify.as_deref(), Some("https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC"));
+    }
+
+    #[tokio::test]
+    async fn keys_tracks_without_a_link() {
+        let mut response: serde_json::Value = serde_json::from_str(MATCH).unwrap();
+        response["result"]["song_link"] = serde_json::Value::Null;
+        let stub = Stub::start(respond_with(&response.to_string())).await;
+        assert_eq!(recognize(&stub).await.unwrap().track_key, "audd:isrc:GBXYZ2100017");
+
+        response["result"]["apple_music"] = serde_json::Value::Null;
+        let stub = Stub::start(respond_with(&response.to_string())).await;
+        let key = recognize(&stub).await.unwrap().track_key;
+        assert!(key.starts_with("audd:") && key.len() > "audd:".len(), "{}", key);
+        // Stable from one match to the next, but not shared with other songs
+        assert_eq!(recognize(&stub).await.unwrap().track_key, key);
+
+        response["result"]["title"] = "Harbour Lights".into();
+        let stub = Stub::start(respond_with(&response.to_string())).await;
+        assert_ne!(recognize(&stub).await.unwrap().track_key, key);
+    }
+
+    #[tokio::test]
+    async fn maps_errors() {
+        let stub = Stub::start(respond_with(r#"{"status": "success", "result": null}"#)).await;
+        assert!(matches!(recognize(&stub).await, Err(RecognitionError::NoMatch { .. })));
+
+        for code in LIMIT_REACHED {
+            let body = format!(r#"{{"status": "error", "error": {{"error_code": {}, "error_message": "Limit reached"}}}}"#, code);
+            let stub = Stub::start(respond_with(&body)).await;
+            assert!(matches!(recognize(&stub).await, Err(RecognitionError::Throttled { .. })), "{}", code);
+        }
+
+        let stub = Stub::start(respond_with(r#"{"status": "error", "error": {"error_code": 900, "error_message": "Wrong API token"}}"#)).await;
+        assert!(matches!(recognize(&stub).await, Err(RecognitionError::Api { code: 900, .. })));
+    }
+}
@@ -0,0 +1,91 @@
+use std::thread;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{Device, StreamConfig};
+use ringbuf::traits::Producer;
+use ringbuf::HeapProd;
+use tokio::sync::broadcast;
+
+use crate::clock::CaptureClock;
+use crate::events::Event;
+
+/// Captures from `device` into `producer` as 16 kHz mono until the stream fails,
+/// keeping `clock` up to date with when it was recorded. Stream errors that mean the device is gone are published as [`Event::InputLost`].
+pub async fn record_audio(
+    mut producer: HeapProd<i16>,
+    clock: CaptureClock,
+    device: &Device,
+    config: &StreamConfig,
+    events: broadcast::Sender<Event>,
+) -> anyhow::Result<()> {
+    let sample_rate = config.sample_rate.0;
+    let input_data_fn = move |data: &[i16], info: &cpal::InputCallbackInfo| {
+        // The device timestamps the start of the buffer, we want how long ago the end of it was
+        let timestamp = info.timestamp();
+        let latency = timestamp.callback.duration_since(&timestamp.capture).unwrap_or_default();
+
+        // let mut output_fell_behind = false;
+        let data = samples_to_16khz(stereo_pcm_to_mono(data), sample_rate);
+        let length = Duration::from_secs_f64(data.len() as f64 / 16_000.0);
+        let pushed = producer.push_slice(&data);
+        clock.written(pushed, latency.saturating_sub(length));
+        if pushed == 0 {
+            // output_fell_behind = true;
+        }
+        // if output_fell_behind {
+        //     eprintln!("output stream fell behind: try increasing latency");
+        // }
+    };
+
+    let err_fn = move |err: cpal::StreamError| {
+        eprintln!("an error occurred on stream: {}", err);
+        if let cpal::StreamError::DeviceNotAvailable = err {
+            let _ = events.send(Event::InputLost(err.to_string()));
+        }
+    };
+
+    let input_stream = device.build_input_stream(&config, input_data_fn, err_fn, None)?;
+
+    input_stream.play()?;
+
+    loop {
+        thread::sleep(std::time::Duration::from_secs(1));
+    }
+    // drop(input_stream);
+
+    // Ok(())
+}
+
+fn stereo_pcm_to_mono(pcm: &[i16]) -> Vec<i16> {
+    let mut mono = Vec::with_capacity(pcm.len() / 2);
+
+    for i in (0..pcm.len()).step_by(2) {
+        let sample = (pcm[i] as i32 + pcm[i + 1] as i32) / 2;
+        mono.push(sample as i16);
+    }
+
+    mono
+}
+
+fn samples_to_16khz(samples: Vec<i16>, in_sample_rate: u32) -> Vec<i16> {
+    if in_sample_rate == 16_000 {
+        return samples;
+    }
+
+    if in_sample_rate % 16_000 != 0 {
+        panic!("The input sample rate must be a multiple of 16_000");
+    }
+
+    let samples_to_merge = (in_sample_rate / 16_000) as usize;
+
+    let mut res = Vec::with_capacity(samples.len() / samples_to_merge);
+    for i in (0..samples.len()).step_by(samples_to_merge) {
+        let mut sum: i32 = 0;
+        for j in 0..samples_to_merge {
+            sum += samples[i + j] as i32;
+        }
+        res.push((sum / samples_to_merge as i32) as i16)
+    }
+    res
+}
@@ -0,0 +1,72 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+const SAMPLE_RATE: u32 = 16_000;
+
+/// Keeps track of when the samples going through the capture ring buffer were
+/// recorded, so a signature can be timestamped with when its audio was heard
+/// rather than when we got round to popping it.
+///
+/// The producer calls [`CaptureClock::written`] after every push and the consumer
+/// [`CaptureClock::read`] after every pop. Times come from a monotonic clock
+/// anchored to the wall clock once, so they don't jump if the system time does.
+#[derive(Clone)]
+pub struct CaptureClock {
+    anchor: (Instant, SystemTime),
+    state: Arc<Mutex<ClockState>>,
+}
+
+#[derive(Default)]
+struct ClockState {
+    /// 16 kHz samples pushed and popped so far.
+    written: u64,
+    read: u64,
+    /// When the last pushed sample was captured.
+    written_until: Option<SystemTime>,
+}
+
+impl CaptureClock {
+    pub fn new() -> CaptureClock {
+        CaptureClock {
+            anchor: (Instant::now(), SystemTime::now()),
+            state: Arc::default(),
+        }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.anchor.1 + self.anchor.0.elapsed()
+    }
+
+    /// `samples` (16 kHz) were just pushed, the last of them captured
+    /// `latency` ago. Use zero if the source doesn't know.
+    pub fn written(&self, samples: usize, latency: Duration) {
+        let ends_at = self.now().checked_sub(latency).unwrap_or(self.anchor.1);
+
+        let mut state = self.state.lock().unwrap();
+        state.written += samples as u64;
+        state.written_until = Some(ends_at);
+    }
+
+    /// `samples` were just popped. Returns when the last of them was captured,
+    /// or now if the producer doesn't keep the clock.
+    pub fn read(&self, samples: usize) -> SystemTime {
+        let mut state = self.state.lock().unwrap();
+        state.read += samples as u64;
+
+        match state.written_until {
+            // Whatever was pushed since the pop is still in the buffer
+            Some(written_until) => written_until - samples_duration(state.written.saturating_sub(state.read)),
+            None => self.now(),
+        }
+    }
+}
+
+impl Default for CaptureClock {
+    fn default() -> Self {
+        CaptureClock::new()
+    }
+}
+
+fn samples_duration(samples: u64) -> Duration {
+    Duration::from_secs_f64(samples as f64 / SAMPLE_RATE as f64)
+}
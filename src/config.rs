@@ -0,0 +1,402 @@
+use std::{env, fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_CONFIG_PATH: &str = "song_id.json";
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Where caches and other persistent state are kept.
+    pub data_dir: PathBuf,
+    pub cache: CacheConfig,
+    pub offline_queue: OfflineQueueConfig,
+    pub http: HttpConfig,
+    pub budget: BudgetConfig,
+    pub identity: IdentityConfig,
+    pub recognition: RecognitionConfig,
+    pub tracker: TrackerConfig,
+    pub covers: CoversConfig,
+    pub musicbrainz: MusicBrainzConfig,
+    pub discord: DiscordConfig,
+    /// Recognisers to try, in order, until one of them finds the song.
+    pub backends: Vec<BackendKind>,
+    pub audd: Option<AudDConfig>,
+    pub acrcloud: Option<AcrCloudConfig>,
+    pub scrobbler: Option<ScrobblerConfig>,
+    /// Outputs to send events to.
+    pub sinks: Vec<SinkKind>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from(".song_id"),
+            cache: CacheConfig::default(),
+            offline_queue: OfflineQueueConfig::default(),
+            http: HttpConfig::default(),
+            budget: BudgetConfig::default(),
+            identity: IdentityConfig::default(),
+            recognition: RecognitionConfig::default(),
+            tracker: TrackerConfig::default(),
+            covers: CoversConfig::default(),
+            musicbrainz: MusicBrainzConfig::default(),
+            discord: DiscordConfig::default(),
+            backends: vec![BackendKind::Shazam],
+            audd: None,
+            acrcloud: None,
+            scrobbler: None,
+            sinks: vec![SinkKind::Console, SinkKind::Discord, SinkKind::History],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Minimum fraction (0-1) of a window's peak pairs that also occur in the
+    /// last matched one (see `DecodedSignature::landmarks`) for it to be
+    /// treated as a continuation of the same track.
+    pub similarity_threshold: f32,
+    /// How many windows in a row can be extrapolated before asking Shazam again.
+    pub max_extrapolated_windows: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            similarity_threshold: 0.05,
+            max_extrapolated_windows: 4,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct OfflineQueueConfig {
+    /// Delay before retrying queued signatures, doubled after every failed attempt.
+    pub retry_initial_secs: u64,
+    pub retry_max_secs: u64,
+    /// Oldest signatures are dropped beyond this.
+    pub max_entries: usize,
+}
+
+impl Default for OfflineQueueConfig {
+    fn default() -> Self {
+        OfflineQueueConfig {
+            retry_initial_secs: 5,
+            retry_max_secs: 300,
+            max_entries: 500,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+    /// How long pooled connections are kept around while idle.
+    pub pool_idle_timeout_secs: u64,
+    /// `http://`, `https://`, `socks5://` or `socks5h://` proxy URL. The usual
+    /// `HTTPS_PROXY` / `ALL_PROXY` environment variables are honoured when unset.
+    pub proxy: Option<String>,
+    /// Extra PEM-encoded CA certificates to trust.
+    pub ca_bundle: Option<PathBuf>,
+    /// Base URL of the Shazam recognition API.
+    pub base_url: String,
+    /// Base URL for track lookups and search.
+    pub web_base_url: String,
+    /// Attempts per request when the connection fails or the server errors.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after each one.
+    pub retry_base_ms: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            timeout_secs: 20,
+            connect_timeout_secs: 10,
+            pool_idle_timeout_secs: 90,
+            proxy: None,
+            ca_bundle: None,
+            base_url: "https://amp.shazam.com".to_string(),
+            web_base_url: "https://www.shazam.com".to_string(),
+            max_attempts: 3,
+            retry_base_ms: 500,
+        }
+    }
+}
+
+/// Limits on recognition requests, shared by everything using the same `data_dir`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct BudgetConfig {
+    /// `null` for no limit. The default is less than the 300 an hour a new
+    /// song every 12 s window would take, once half of it is gone the
+    /// listener slows down instead (windows continuing a match are free).
+    pub requests_per_hour: Option<u32>,
+    pub requests_per_day: Option<u32>,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        BudgetConfig {
+            requests_per_hour: Some(200),
+            requests_per_day: Some(2000),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RecognitionConfig {
+    /// Length of the audio window recognised at a time.
+    pub window_secs: u64,
+    /// Matches whose best candidate scores below this (0-1) are treated as no match.
+    pub min_confidence: f32,
+    /// Fetch the album tracklist to predict when the track ends and what's next.
+    pub predict_next_track: bool,
+}
+
+impl Default for RecognitionConfig {
+    fn default() -> Self {
+        RecognitionConfig {
+            window_secs: 12,
+            min_confidence: 0.0,
+            predict_next_track: true,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct TrackerConfig {
+    /// Windows in a row that have to agree on a new track before we switch to it.
+    pub confirmations: u32,
+    /// Windows in a row without a match (or silent) before the track is considered over.
+    pub failures_to_clear: u32,
+    /// How far apart the start times implied by two matches of the same track
+    /// can be for them to count as the same play of it.
+    pub start_tolerance_secs: f32,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        TrackerConfig {
+            confirmations: 2,
+            failures_to_clear: 3,
+            start_tolerance_secs: 10.0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CoversConfig {
+    pub enabled: bool,
+    /// Square sizes (in pixels) to keep of every cover.
+    pub sizes: Vec<u32>,
+    /// Oldest covers are deleted once the cache grows past this.
+    pub max_cache_mb: u64,
+}
+
+impl Default for CoversConfig {
+    fn default() -> Self {
+        CoversConfig {
+            enabled: true,
+            sizes: vec![100, 400, 1000],
+            max_cache_mb: 200,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct MusicBrainzConfig {
+    pub enabled: bool,
+    pub base_url: String,
+    /// MusicBrainz asks for an application name, version and contact address here.
+    pub user_agent: String,
+    /// Minimum delay between requests. Their servers allow one per second on average.
+    pub min_interval_ms: u64,
+}
+
+impl Default for MusicBrainzConfig {
+    fn default() -> Self {
+        MusicBrainzConfig {
+            enabled: true,
+            base_url: "https://musicbrainz.org/ws/2".to_string(),
+            user_agent: concat!("song_id/", env!("CARGO_PKG_VERSION")).to_string(),
+            min_interval_ms: 1000,
+        }
+    }
+}
+
+/// What the Discord activity shows. Text fields are templates: `{song}`,
+/// `{artist}`, `{album}`, `{year}`, `{genre}`, `{label}`, `{isrc}`,
+/// `{track_key}`, `{share_url}`, `{apple_music}`, `{spotify}`,
+/// `{youtube_music}`, `{confidence}`, `{track_number}`, `{disc_number}`,
+/// `{medium_format}` and `{release_date}` are replaced with whatever we know
+/// about the track, or nothing. Fields that end up empty are left out.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct DiscordConfig {
+    /// Application ID from the Discord developer portal. Its name is what shows after "Listening to".
+    pub app_id: i64,
+    pub details: String,
+    pub state: String,
+    pub large_text: String,
+    /// Asset key or image URL for the small picture in the corner. `small_text` is only shown with it.
+    pub small_image: Option<String>,
+    pub small_text: String,
+    /// Up to two. Buttons whose URL comes out empty, or over the 512 bytes Discord allows, are skipped.
+    pub buttons: Vec<DiscordButton>,
+}
+
+impl Default for DiscordConfig {
+    fn default() -> Self {
+        DiscordConfig {
+            app_id: 1236161402050183238,
+            details: "{song}".to_string(),
+            state: "{artist}".to_string(),
+            large_text: "{album}".to_string(),
+            small_image: None,
+            small_text: String::new(),
+            buttons: vec![DiscordButton {
+                label: "Open in Shazam".to_string(),
+                url: "{share_url}".to_string(),
+            }],
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DiscordButton {
+    pub label: String,
+    pub url: String,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    Shazam,
+    Audd,
+    Acrcloud,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    Console,
+    Discord,
+    History,
+    Scrobbler,
+}
+
+#[derive(Deserialize)]
+pub struct AudDConfig {
+    pub api_token: String,
+    #[serde(default = "default_audd_base_url")]
+    pub base_url: String,
+}
+
+fn default_audd_base_url() -> String {
+    "https://api.audd.io".to_string()
+}
+
+#[derive(Deserialize)]
+pub struct AcrCloudConfig {
+    /// e.g. `identify-eu-west-1.acrcloud.com`, as shown in the project console.
+    pub host: String,
+    pub access_key: String,
+    pub access_secret: String,
+    /// Defaults to `https://<host>`.
+    pub base_url: Option<String>,
+}
+
+/// Last.fm, or anything speaking its API like Libre.fm.
+#[derive(Deserialize)]
+pub struct ScrobblerConfig {
+    pub api_key: String,
+    pub api_secret: String,
+    /// From `auth.getMobileSession` or the desktop auth flow.
+    pub session_key: String,
+    /// `https://libre.fm/2.0/` for Libre.fm.
+    #[serde(default = "default_scrobbler_api_root")]
+    pub api_root: String,
+}
+
+fn default_scrobbler_api_root() -> String {
+    "https://ws.audioscrobbler.com/2.0/".to_string()
+}
+
+/// What we tell Shazam about ourselves.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct IdentityConfig {
+    /// Set to `null` to leave geolocation out of requests entirely.
+    pub geolocation: Option<Geolocation>,
+    pub timezone: String,
+    /// Language and country of the catalogue the metadata comes from.
+    pub language: String,
+    pub country: String,
+    pub content_language: String,
+    /// Picked at random from a list of Android user agents for every request when unset.
+    pub user_agent: Option<String>,
+    /// Fixed installation UUID. When unset, a new one is made for every request
+    /// unless `persist_installation_id` is on, in which case one is generated
+    /// once and kept in `data_dir`.
+    pub installation_id: Option<String>,
+    pub persist_installation_id: bool,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        IdentityConfig {
+            geolocation: Some(Geolocation::default()),
+            timezone: "Europe/London".to_string(),
+            language: "en".to_string(),
+            country: "US".to_string(),
+            content_language: "en_US".to_string(),
+            user_agent: None,
+            installation_id: None,
+            persist_installation_id: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Geolocation {
+    pub altitude: f64,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Default for Geolocation {
+    fn default() -> Self {
+        Geolocation {
+            altitude: 300.0,
+            latitude: 45.0,
+            longitude: 2.0,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from `song_id.json` (or `$SONG_ID_CONFIG`), falling back to defaults if it doesn't exist.
+    /// Also returns the path it was read from, `None` if it's all defaults.
+    pub fn load() -> Result<(Config, Option<String>), String> {
+        let path = env::var("SONG_ID_CONFIG").unwrap_or(DEFAULT_CONFIG_PATH.to_string());
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => return Ok((Config::default(), None)),
+        };
+
+        let config = serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+
+        Ok((config, Some(path)))
+    }
+}
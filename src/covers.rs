@@ -0,0 +1,204 @@
+use std::fs;
+use std::path::PathBuf;
+
+use regex::Regex;
+use reqwest::Client;
+use sha1::{Digest, Sha1};
+
+use crate::config::Config;
+use crate::utils::write_atomic;
+
+const COVERS_DIR_NAME: &str = "covers";
+
+/// Cover art downloaded once per track and kept on disk, in a few sizes if the
+/// server can resize it.
+pub struct CoverCache {
+    dir: PathBuf,
+    client: Client,
+    sizes: Vec<u32>,
+    max_bytes: u64,
+}
+
+impl CoverCache {
+    pub fn new(config: &Config, client: Client) -> CoverCache {
+        let mut sizes = config.covers.sizes.clone();
+        sizes.sort();
+
+        CoverCache {
+            dir: config.data_dir.join(COVERS_DIR_NAME),
+            client,
+            sizes,
+            max_bytes: config.covers.max_cache_mb * 1024 * 1024,
+        }
+    }
+
+    pub fn path(&self, track_key: &str, size: u32) -> PathBuf {
+        self.dir.join(format!("{}_{}.jpg", file_stem(track_key), size))
+    }
+
+    /// For covers that only come in one size.
+    fn original_path(&self, track_key: &str) -> PathBuf {
+        self.dir.join(format!("{}.jpg", file_stem(track_key)))
+    }
+
+    /// Downloads whatever variants we don't have yet and returns the path of the
+    /// largest one. Variants already on disk are used as they are, so this
+    /// keeps working offline for tracks we've seen before.
+    pub async fn fetch(&self, track_key: &str, url: &str) -> Result<PathBuf, String> {
+        // Asking for other sizes would just get the same image each time
+        let variants: Vec<(PathBuf, String)> = match is_resizable(url) {
+            true => self.sizes.iter().map(|&size| (self.path(track_key, size), resized_url(url, size))).collect(),
+            false => vec![(self.original_path(track_key), url.to_string())],
+        };
+
+        let mut largest = None;
+        let mut downloaded = false;
+
+        for (path, url) in variants {
+            if !path.exists() {
+                let bytes = self.client.get(url)
+                    .send().await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| format!("Failed to download cover: {}", e))?
+                    .bytes().await
+                    .map_err(|e| format!("Failed to download cover: {}", e))?;
+
+                write_atomic(&path, &bytes).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+                downloaded = true;
+            }
+
+            largest = Some(path);
+        }
+
+        if downloaded {
+            self.evict();
+        }
+
+        largest.ok_or("No cover sizes configured".to_string())
+    }
+
+    /// Deletes the least recently written covers until we're under the size limit.
+    fn evict(&self) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        let mut files: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+            })
+            .collect();
+
+        let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+        files.sort();
+
+        for (_, len, path) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= len;
+            }
+        }
+    }
+}
+
+/// Shazam keys are plain numbers, but other backends' can be URLs. Anything
+/// that isn't safe in a file name is replaced, with a bit of a hash of the
+/// original key on the end so two keys can't end up sharing covers.
+fn file_stem(track_key: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if !track_key.is_empty() && track_key.chars().all(safe) {
+        return track_key.to_string();
+    }
+
+    let stem: String = track_key.chars().map(|c| if safe(c) { c } else { '_' }).take(64).collect();
+    let hash: String = Sha1::digest(track_key).iter().take(4).map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}_{}", stem, hash)
+}
+
+/// Shazam cover art comes from the Apple Music CDN, which serves any size you ask
+/// for by changing the `400x400cc.jpg` bit at the end of the URL.
+fn resized_url(url: &str, size: u32) -> String {
+    size_pattern()
+        .replace(url, format!("/{}x{}$1.jpg", size, size))
+        .into_owned()
+}
+
+fn is_resizable(url: &str) -> bool {
+    size_pattern().is_match(url)
+}
+
+fn size_pattern() -> Regex {
+    Regex::new(r"/\d+x\d+(\w*)\.jpg$").unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{temp_dir, Stub};
+
+    #[test]
+    fn keys_make_safe_file_names() {
+        let config = Config::default();
+        let covers = CoverCache::new(&config, Client::new());
+
+        assert_eq!(covers.path("612846392", 400), covers.dir.join("612846392_400.jpg"));
+
+        for key in ["audd:https://lis.tn/PaperLanterns", "acrcloud:6049f11da7095e8bb8266871d4a70873", "../../etc", ".."] {
+            let path = covers.path(key, 400);
+            assert_eq!(path.parent(), Some(covers.dir.as_path()), "{}", key);
+            let name = path.file_name().unwrap().to_str().unwrap();
+            assert!(!name.contains([':', '/', '\\']) && !name.starts_with('.'), "{}", name);
+        }
+
+        // Keys that only differ in the characters that get replaced stay apart
+        assert_ne!(covers.path("audd:a/b", 400), covers.path("audd_a_b", 400));
+        assert_ne!(covers.path("audd:a/b", 400), covers.path("audd:a:b", 400));
+    }
+
+    fn covers(name: &str) -> CoverCache {
+        let config = Config {
+            data_dir: temp_dir(name),
+            ..Config::default()
+        };
+        CoverCache::new(&config, Client::new())
+    }
+
+    fn files(dir: &std::path::Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn keeps_every_size_of_resizable_covers() {
+        let stub = Stub::start(|request| (200, format!("cover from {}", request.target))).await;
+        let covers = covers("covers_sizes");
+
+        let url = format!("{}/image/thumb/Music/v4/11/22/33/cover.jpg/400x400cc.jpg", stub.url);
+        let path = covers.fetch("612846392", &url).await.unwrap();
+        assert_eq!(path, covers.path("612846392", 1000));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "cover from /image/thumb/Music/v4/11/22/33/cover.jpg/1000x1000cc.jpg");
+        assert_eq!(files(&covers.dir), ["612846392_100.jpg", "612846392_1000.jpg", "612846392_400.jpg"]);
+
+        // Already on disk
+        covers.fetch("612846392", &url).await.unwrap();
+        assert_eq!(stub.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn keeps_one_copy_of_other_covers() {
+        let stub = Stub::start(|_| (200, "cover".to_string())).await;
+        let covers = covers("covers_single");
+
+        let url = format!("{}/covers/6049f11da7095e8bb8266871d4a70873.png", stub.url);
+        let path = covers.fetch("612846392", &url).await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "cover");
+        assert_eq!(files(&covers.dir), ["612846392.jpg"]);
+        assert_eq!(stub.requests().len(), 1);
+    }
+}
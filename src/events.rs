@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::broadcast;
+
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+use crate::tracker::Transition;
+
+/// Events buffered per sink before the slowest one starts missing them.
+const CAPACITY: usize = 64;
+
+/// Everything outputs might want to know about, published by the main loop.
+#[derive(Clone)]
+pub enum Event {
+    /// A window was recognised, possibly by extrapolating. Fires every window,
+    /// use `Changed` to act on the track actually changing.
+    Recognized(Arc<SongRecognizedMessage>),
+    Changed(Arc<Transition>),
+    /// About to look up a window of this many 16 kHz samples.
+    Querying { samples: usize },
+    NoMatch,
+    /// A match scoring under `recognition.min_confidence`, treated as no match.
+    LowConfidence(Arc<SongRecognizedMessage>),
+    /// The last windows didn't agree with the current track, which is kept for now.
+    Uncertain(Arc<SongRecognizedMessage>),
+    /// Where the current track is on its album, going by the tracklist.
+    /// `next` is the track number and name of the one after, `None` at the end of the side.
+    Predicted { track_number: u32, next: Option<(u32, String)>, ends_at: SystemTime },
+    /// The predicted end of the track came, the next window starts afresh.
+    PredictedEnd,
+    /// We're offline, the window was queued to be retried later.
+    Queued,
+    /// How many windows are waiting in the offline queue. Sent at startup if
+    /// some were left over, and whenever it changes.
+    QueueLength(usize),
+    /// A queued window was recognised after all. `timestamp` is when it was captured.
+    LateMatch(Arc<SongRecognizedMessage>),
+    /// A queued window was given up on, and why.
+    DroppedFromQueue(String),
+    /// The input went silent.
+    Silence,
+    Error(String),
+    /// The audio stream failed, e.g. because the device was unplugged.
+    InputLost(String),
+    /// Not querying again for this long.
+    Throttled(Duration),
+    /// The request `budget` is used up, the next request is allowed in this long.
+    BudgetExhausted(Duration),
+    /// One of several backends failed, the next one is tried.
+    BackendFailed { backend: &'static str, error: String },
+    /// Sent once before exiting, the last chance for sinks to clean up.
+    Shutdown,
+}
+
+pub fn channel() -> broadcast::Sender<Event> {
+    broadcast::channel(CAPACITY).0
+}
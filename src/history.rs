@@ -0,0 +1,94 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+
+const HISTORY_FILE_NAME: &str = "history.jsonl";
+
+#[derive(Serialize)]
+struct HistoryEntry<'a> {
+    /// Unix time (seconds) the audio was captured at.
+    timestamp: u64,
+    track_key: &'a str,
+    artist_name: &'a str,
+    song_name: &'a str,
+    album_name: Option<&'a str>,
+    isrc: Option<&'a str>,
+    label: Option<&'a str>,
+    share_url: Option<&'a str>,
+    recording_mbid: Option<&'a str>,
+    release_mbid: Option<&'a str>,
+    track_seek: Option<f32>,
+    /// Recognised after the fact from the offline queue.
+    late: bool,
+}
+
+/// Append-only log of recognised tracks, one JSON object per line.
+pub struct History {
+    path: PathBuf,
+    last_track_key: Option<String>,
+}
+
+impl History {
+    pub fn new(config: &Config) -> History {
+        History {
+            path: config.data_dir.join(HISTORY_FILE_NAME),
+            last_track_key: None,
+        }
+    }
+
+    /// Records a live recognition, unless it's the track we recorded last.
+    pub fn record(&mut self, song: &SongRecognizedMessage) {
+        if self.last_track_key.as_deref() == Some(song.track_key.as_str()) {
+            return;
+        }
+        self.last_track_key = Some(song.track_key.clone());
+
+        self.append(song, false);
+    }
+
+    /// Records a recognition that came back from the offline queue, at the time it was captured.
+    pub fn record_late(&mut self, song: &SongRecognizedMessage) {
+        self.append(song, true);
+    }
+
+    pub fn forget_last_track(&mut self) {
+        self.last_track_key = None;
+    }
+
+    fn append(&self, song: &SongRecognizedMessage, late: bool) {
+        let entry = HistoryEntry {
+            timestamp: song.timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs(),
+            track_key: &song.track_key,
+            artist_name: &song.artist_name,
+            song_name: &song.song_name,
+            album_name: song.album_name.as_deref(),
+            isrc: song.isrc.as_deref(),
+            label: song.label.as_deref(),
+            share_url: song.share_url.as_deref(),
+            recording_mbid: song.musicbrainz.as_ref().map(|info| info.recording_id.as_str()),
+            release_mbid: song.musicbrainz.as_ref().map(|info| info.release_id.as_str()),
+            track_seek: song.track_seek,
+            late,
+        };
+
+        if let Some(parent) = self.path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&entry).unwrap()));
+
+        if let Err(e) = res {
+            eprintln!("[HISTORY] Failed to write {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Song recognition from live audio, mostly through Shazam.
+//!
+//! The pieces can be used on their own:
+//!
+//! - [`SignatureGenerator`] turns 16 kHz mono samples into a [`DecodedSignature`],
+//!   which can be encoded to and decoded from Shazam's `data:` URIs and binary format.
+//! - [`Recognizer`] sends signatures to Shazam and looks tracks up, and
+//!   [`try_recognize_song`] turns a signature into a [`SongRecognizedMessage`].
+//! - [`Listener`] runs the whole pipeline over a ring buffer of captured audio,
+//!   with caching, the offline queue, track change detection and so on, and
+//!   publishes the results as [`Event`]s or a `Stream` of recognitions.
+//!
+//! Which parts are compiled in is picked with cargo features: `discord`,
+//! `capture`, `shazam-client`, `local-db`, `scrobbler` (all on by default) and `ffmpeg`.
+
+#[cfg(feature = "shazam-client")]
+pub mod backends;
+#[cfg(feature = "capture")]
+pub mod capture;
+pub mod clock;
+pub mod config;
+#[cfg(feature = "shazam-client")]
+pub mod covers;
+pub mod events;
+#[cfg(feature = "local-db")]
+pub mod history;
+#[cfg(all(feature = "shazam-client", feature = "local-db"))]
+pub mod listener;
+#[cfg(feature = "shazam-client")]
+pub mod musicbrainz;
+#[cfg(feature = "discord")]
+pub mod presence;
+#[cfg(feature = "scrobbler")]
+pub mod scrobbler;
+pub mod shazam;
+pub mod sinks;
+#[cfg(test)]
+mod testing;
+pub mod tracker;
+pub mod utils;
+
+pub use clock::CaptureClock;
+pub use config::Config;
+pub use events::Event;
+#[cfg(all(feature = "shazam-client", feature = "local-db"))]
+pub use listener::Listener;
+#[cfg(feature = "shazam-client")]
+pub use shazam::core::http::try_recognize_song;
+pub use shazam::core::thread_messages::SongRecognizedMessage;
+#[cfg(feature = "shazam-client")]
+pub use shazam::error::RecognitionError;
+pub use shazam::fingerprinting::algorithm::SignatureGenerator;
+#[cfg(feature = "shazam-client")]
+pub use shazam::fingerprinting::communication::Recognizer;
+pub use shazam::fingerprinting::signature_format::DecodedSignature;
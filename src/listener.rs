@@ -0,0 +1,323 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use ringbuf::traits::Consumer;
+use ringbuf::HeapCons;
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::backends::{try_recognize_song_cached, Backend};
+use crate::clock::CaptureClock;
+use crate::config::Config;
+use crate::covers::CoverCache;
+use crate::events::{self, Event};
+use crate::musicbrainz::MusicBrainz;
+use crate::shazam::core::album::{predict_next_track, AlbumCache};
+use crate::shazam::core::cache::TrackCache;
+use crate::shazam::core::offline_queue::{spawn_retry_worker, OfflineQueue};
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+use crate::shazam::error::RecognitionError;
+use crate::shazam::fingerprinting::algorithm::SignatureGenerator;
+use crate::shazam::fingerprinting::communication::Recognizer;
+use crate::tracker::{PlaybackState, Tracker};
+
+/// The whole recognition pipeline: takes a window of audio every
+/// `recognition.window_secs`, recognises it (from the cache, the configured
+/// backends, or later from the offline queue), works out when the track
+/// changes and publishes all of it as [`Event`]s.
+///
+/// Subscribe with [`Listener::events`] or [`Listener::recognitions`] before
+/// calling [`Listener::start`] so nothing is missed.
+///
+/// ```no_run
+/// use song_id::{CaptureClock, Config, Listener};
+/// use tokio_stream::StreamExt;
+///
+/// # async fn run(audio: ringbuf::HeapCons<i16>, clock: CaptureClock) -> Result<(), String> {
+/// let mut listener = Listener::new(&Config::default())?;
+/// let mut recognitions = Box::pin(listener.recognitions());
+/// listener.start(audio, clock);
+///
+/// while let Some(song) = recognitions.next().await {
+///     println!("{} - {}", song.song_name, song.artist_name);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Listener {
+    events: broadcast::Sender<Event>,
+    pipeline: Option<Pipeline>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+struct Pipeline {
+    recognizer: Recognizer,
+    backends: Arc<Vec<Backend>>,
+    cache: TrackCache,
+    queue: Arc<Mutex<OfflineQueue>>,
+    albums: AlbumCache,
+    covers: Option<CoverCache>,
+    musicbrainz: Option<MusicBrainz>,
+    tracker: Tracker,
+
+    window: Duration,
+    min_confidence: f32,
+    predict_next: bool,
+    retry_initial: Duration,
+    retry_max: Duration,
+}
+
+impl Listener {
+    /// Sets up the HTTP client, backends and caches. Fails on invalid settings.
+    pub fn new(config: &Config) -> Result<Listener, String> {
+        let recognizer = Recognizer::new(config)?;
+        let backends = Backend::from_config(config, &recognizer)?;
+
+        Ok(Listener {
+            events: events::channel(),
+            pipeline: Some(Pipeline {
+                backends: Arc::new(backends),
+                cache: TrackCache::load(config),
+                queue: Arc::new(Mutex::new(OfflineQueue::load(config))),
+                albums: AlbumCache::load(config),
+                covers: config.covers.enabled.then(|| CoverCache::new(config, recognizer.client())),
+                musicbrainz: config.musicbrainz.enabled.then(|| MusicBrainz::new(config, recognizer.client())),
+                tracker: Tracker::new(config),
+                window: Duration::from_secs(config.recognition.window_secs),
+                min_confidence: config.recognition.min_confidence,
+                predict_next: config.recognition.predict_next_track,
+                retry_initial: Duration::from_secs(config.offline_queue.retry_initial_secs),
+                retry_max: Duration::from_secs(config.offline_queue.retry_max_secs),
+                recognizer,
+            }),
+            tasks: vec![],
+        })
+    }
+
+    /// The bus everything is published on. Clone it to publish events of your
+    /// own, e.g. [`Event::InputLost`] from the audio capture.
+    pub fn events(&self) -> &broadcast::Sender<Event> {
+        &self.events
+    }
+
+    /// Every recognised window, including extrapolated ones and late matches
+    /// from the offline queue (which have an old `timestamp`).
+    pub fn recognitions(&self) -> impl Stream<Item = Arc<SongRecognizedMessage>> {
+        BroadcastStream::new(self.events.subscribe()).filter_map(|event| match event {
+            Ok(Event::Recognized(song) | Event::LateMatch(song)) => Some(song),
+            _ => None,
+        })
+    }
+
+    /// Starts listening to `audio`, which should be filled with 16 kHz mono
+    /// samples as they are captured, with `clock` told about every push (see
+    /// [`CaptureClock`]). Does nothing if already started.
+    pub fn start(&mut self, audio: HeapCons<i16>, clock: CaptureClock) {
+        let Some(pipeline) = self.pipeline.take() else {
+            return;
+        };
+
+        self.tasks.push(spawn_retry_worker(pipeline.backends.clone(), pipeline.queue.clone(), self.events.clone(), pipeline.retry_initial, pipeline.retry_max));
+        self.tasks.push(tokio::spawn(pipeline.run(audio, clock, self.events.clone())));
+    }
+
+    /// Stops listening. Events already published are still delivered.
+    pub fn stop(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Pipeline {
+    async fn run(self, mut consumer: HeapCons<i16>, clock: CaptureClock, events: broadcast::Sender<Event>) {
+        let Pipeline {
+            recognizer,
+            backends,
+            mut cache,
+            queue,
+            mut albums,
+            covers,
+            mut musicbrainz,
+            mut tracker,
+            window,
+            min_confidence,
+            predict_next,
+            ..
+        } = self;
+
+        // Queued windows only need their audio if something other than Shazam will retry them
+        let keep_audio = backends.iter().any(Backend::needs_audio);
+
+        let publish = |event: Event| {
+            // Only fails when nobody is subscribed
+            let _ = events.send(event);
+        };
+
+        let mut was_empty_last = false;
+        // Shazam sometimes asks us to hold off for a while
+        let mut next_query_at = Instant::now();
+        let mut predicted_end: Option<SystemTime> = None;
+        loop {
+            // If the track is about to end, wait for it to do so and start the
+            // next window on a clean slate so it only contains the new track
+            if let Some(ends_at) = predicted_end {
+                if let Ok(until_end) = ends_at.duration_since(SystemTime::now()) {
+                    if until_end < window {
+                        tokio::time::sleep(until_end).await;
+                        clock.read(consumer.pop_iter().count());
+                        cache.forget_last_match();
+                        predicted_end = None;
+                        publish(Event::PredictedEnd);
+                    }
+                }
+            }
+
+            tokio::time::sleep(window).await;
+            let popped: Vec<i16> = consumer.pop_iter().collect();
+            let captured_at = clock.read(popped.len());
+            if popped.is_empty() || popped.iter().all(|&x| x <= 16) {
+                if !was_empty_last {
+                    publish(Event::Silence);
+                }
+                was_empty_last = true;
+                cache.forget_last_match();
+
+                if let Some(transition) = tracker.observe_silence() {
+                    publish(Event::Changed(Arc::new(transition)));
+                }
+                continue;
+            }
+
+            was_empty_last = false;
+
+            // Already announced with Event::Throttled
+            if Instant::now() < next_query_at {
+                continue;
+            }
+
+            publish(Event::Querying { samples: popped.len() });
+
+            let fingerprint = SignatureGenerator::make_signature_from_buffer(&popped);
+            let signature_uri = fingerprint.encode_to_uri();
+            let on_failure = |backend: &Backend, e: &RecognitionError| publish(Event::BackendFailed { backend: backend.name(), error: e.to_string() });
+            let res = try_recognize_song_cached(&backends, &mut cache, &popped, fingerprint, captured_at, on_failure).await;
+            let from_network = !matches!(&res, Ok(song) if song.extrapolated);
+            match res {
+                Ok(song) if song.confidence().is_some_and(|confidence| confidence < min_confidence) => {
+                    publish(Event::LowConfidence(Arc::new(song)));
+                    cache.forget_last_match();
+                    if let Some(transition) = tracker.observe_failure() {
+                        publish(Event::Changed(Arc::new(transition)));
+                    }
+                }
+                Ok(mut song) => {
+                    if let (Some(covers), Some(cover_image)) = (&covers, &song.cover_image) {
+                        match covers.fetch(&song.track_key, cover_image).await {
+                            Ok(path) => song.local_cover = Some(path),
+                            Err(e) => publish(Event::Error(e)),
+                        }
+                    }
+
+                    if let Some(musicbrainz) = &mut musicbrainz {
+                        if song.musicbrainz.is_none() && !song.extrapolated {
+                            match musicbrainz.lookup(&song).await {
+                                Ok(Some(info)) => {
+                                    cache.set_musicbrainz(&song.track_key, info.clone());
+                                    song.musicbrainz = Some(info);
+                                }
+                                Ok(None) => {}
+                                Err(e) => publish(Event::Error(format!("MusicBrainz lookup failed: {}", e))),
+                            }
+                        }
+                    }
+
+                    // Published after the recognition it goes with
+                    let mut predicted = None;
+                    if predict_next {
+                        match predict_next_track(&recognizer, &mut albums, &song).await {
+                            Ok(Some(prediction)) => {
+                                predicted = Some(Event::Predicted {
+                                    track_number: prediction.current.track_number,
+                                    next: prediction.next.map(|next| (next.track_number, next.name)),
+                                    ends_at: prediction.ends_at,
+                                });
+                                song.duration = Some(prediction.current.duration());
+                                predicted_end = Some(prediction.ends_at);
+                            }
+                            Ok(None) => predicted_end = None,
+                            Err(e) => {
+                                publish(Event::Error(format!("Failed to fetch album: {}", e)));
+                                predicted_end = None;
+                            }
+                        }
+                    }
+
+                    // Only as a fallback, the album tracklist is more likely to match the version that's playing
+                    if song.duration.is_none() {
+                        song.duration = song.musicbrainz.as_ref().and_then(|mb| mb.length_ms).map(Duration::from_millis);
+                    }
+
+                    publish(Event::Recognized(Arc::new(song.clone())));
+                    if let Some(predicted) = predicted {
+                        publish(predicted);
+                    }
+
+                    if let Some(transition) = tracker.observe_match(song) {
+                        publish(Event::Changed(Arc::new(transition)));
+                    }
+                }
+                Err(RecognitionError::NoMatch { retry_after }) => {
+                    publish(Event::NoMatch);
+                    if let Some(retry_after) = retry_after {
+                        next_query_at = Instant::now() + retry_after;
+                    }
+                    cache.forget_last_match();
+                    if let Some(transition) = tracker.observe_failure() {
+                        publish(Event::Changed(Arc::new(transition)));
+                    }
+                }
+                Err(RecognitionError::Throttled { retry_after }) => {
+                    let retry_after = retry_after.unwrap_or(Duration::from_secs(60));
+                    publish(Event::Throttled(retry_after));
+                    next_query_at = Instant::now() + retry_after;
+                }
+                Err(RecognitionError::OverBudget { retry_after }) => {
+                    publish(Event::BudgetExhausted(retry_after));
+                    next_query_at = Instant::now() + retry_after;
+                }
+                Err(e) if e.is_offline() => {
+                    publish(Event::Error(e.to_string()));
+                    publish(Event::Queued);
+                    if let Ok(signature_uri) = signature_uri {
+                        let mut queue = queue.lock().await;
+                        if let Err(e) = queue.push(signature_uri, captured_at, keep_audio.then_some(&popped[..])) {
+                            publish(Event::Error(e));
+                        }
+                        publish(Event::QueueLength(queue.len()));
+                    }
+                }
+                Err(e) => {
+                    publish(Event::Error(e.to_string()));
+                    cache.forget_last_match();
+                    if let Some(transition) = tracker.observe_failure() {
+                        publish(Event::Changed(Arc::new(transition)));
+                    }
+                }
+            }
+
+            if let PlaybackState::Uncertain(song) = tracker.state() {
+                publish(Event::Uncertain(Arc::new((**song).clone())));
+            }
+
+            if from_network {
+                if let Some(pacing) = recognizer.pacing() {
+                    publish(Event::Throttled(pacing));
+                    next_query_at = next_query_at.max(Instant::now() + pacing);
+                }
+            }
+        }
+    }
+}
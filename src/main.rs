@@ -1,26 +1,9 @@
-use std::{io, process::exit, sync::Arc, thread};
+use std::{env, process::exit};
 
-use tokio::{signal, sync::Mutex};
-
-use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    Device, StreamConfig,
-};
-use ringbuf::{
-    storage::Heap,
-    traits::{Consumer, Producer, Split},
-    wrap::caching::Caching,
-    HeapRb, SharedRb,
-};
-
-mod presence;
-use presence::make_client;
-
-mod shazam;
-use shazam::core::http::try_recognize_song;
-use shazam::fingerprinting::algorithm::SignatureGenerator;
-
-use crate::presence::update_presence;
+use song_id::config::Config;
+use song_id::shazam::core::cache::TrackCache;
+use song_id::shazam::core::http::refresh_cached_track;
+use song_id::shazam::fingerprinting::communication::Recognizer;
 
 pub fn to_bytes(input: &[i16]) -> Vec<u8> {
     let mut bytes = Vec::with_capacity(2 * input.len());
@@ -34,6 +17,68 @@ pub fn to_bytes(input: &[i16]) -> Vec<u8> {
 
 #[tokio::main]
 async fn main() {
+    let (config, path) = Config::load().unwrap_or_else(|e| {
+        eprintln!("[CONFIG] {}", e);
+        exit(1);
+    });
+    if let Some(path) = path {
+        println!("[CONFIG] Loaded config from {}", path);
+    }
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("search") => {
+            search(&recognizer(&config), &args[1..].join(" ")).await;
+            exit(0);
+        }
+        #[cfg(feature = "ffmpeg")]
+        Some("file") => {
+            let recognizer = recognizer(&config);
+            for path in &args[1..] {
+                recognize_file(&recognizer, path, config.recognition.window_secs as u32).await;
+            }
+            exit(0);
+        }
+        Some("lookup") => {
+            let recognizer = recognizer(&config);
+            let mut cache = TrackCache::load(&config);
+            for track_key in &args[1..] {
+                lookup(&recognizer, &mut cache, track_key).await;
+            }
+            exit(0);
+        }
+        _ => {}
+    }
+
+    listen(&config).await;
+}
+
+#[cfg(not(feature = "capture"))]
+async fn listen(_config: &Config) {
+    eprintln!("Built without the `capture` feature, so only `search`, `lookup` and `file` are available.");
+    exit(1);
+}
+
+/// Listens to an input device until Ctrl+C.
+#[cfg(feature = "capture")]
+async fn listen(config: &Config) {
+    use std::{io, time::Duration};
+
+    use cpal::traits::{DeviceTrait, HostTrait};
+    use ringbuf::{traits::Split, HeapRb};
+    use tokio::signal;
+
+    use song_id::capture::record_audio;
+    use song_id::clock::CaptureClock;
+    use song_id::events::Event;
+    use song_id::listener::Listener;
+    use song_id::sinks;
+
+    let mut listener = Listener::new(config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+
     let host = cpal::default_host();
 
     let mut devices = host.input_devices().expect("No input devices available.").collect::<Vec<_>>();
@@ -81,69 +126,34 @@ async fn main() {
 
     println!("Using device: {}", device.name().unwrap());
 
-    let config = device
+    let stream_config = device
         .default_input_config()
         .expect("no default input config")
         .config();
 
-    let seconds_per_read = 12;
+    let seconds_per_read = config.recognition.window_secs;
 
     // Create a delay in case the input and output devices aren't synced.
-    let latency_frames = seconds_per_read as f32 * config.sample_rate.0 as f32;
-    let latency_samples = latency_frames as usize * config.channels as usize;
+    let latency_frames = seconds_per_read as f32 * stream_config.sample_rate.0 as f32;
+    let latency_samples = latency_frames as usize * stream_config.channels as usize;
 
     // The buffer to share samples
     let ring = HeapRb::<i16>::new(latency_samples * 2);
-    let (producer, mut consumer) = ring.split();
+    let (producer, consumer) = ring.split();
+    let clock = CaptureClock::new();
 
-    let client = Arc::new(Mutex::new(
-        make_client(discord_sdk::Subscriptions::ACTIVITY).await,
-    ));
-    let client2 = client.clone();
+    let sinks = sinks::spawn_from_config(config, listener.events()).await;
 
+    let input_events = listener.events().clone();
+    let input_clock = clock.clone();
     let rec_thread = tokio::spawn(async move {
-        record_audio(producer, &device, &config).await.unwrap();
-    });
-
-    let req_thread = tokio::spawn(async move {
-        let mut was_empty_last = false;
-        loop {
-            tokio::time::sleep(std::time::Duration::from_secs(seconds_per_read)).await;
-            let popped: Vec<i16> = consumer.pop_iter().collect();
-            if popped.len() == 0 || popped.iter().all(|&x| x <= 16) {
-                if was_empty_last {
-                    continue;
-                }
-                was_empty_last = true;
-
-                println!("Input stream was empty/silent for entire {}s interval. Clearing activity...", seconds_per_read);
-                client2.lock().await.discord.clear_activity().await.unwrap();
-                continue;
-            }
-
-            was_empty_last = false;
-
-            println!("Looking up with signature from {} samples ({}s)", popped.len(), popped.len() as f32 / 16_000.0);
-
-            let fingerprint = SignatureGenerator::make_signature_from_buffer(&popped);
-            let res = try_recognize_song(fingerprint).await;
-            match res {
-                Ok(song) => {
-                    if let Some(seek) = song.track_seek {
-                        println!("Song recognized: {} - {} @ {}:{:02}", song.song_name, song.artist_name, (seek / 60.0) as u32, (seek % 60.0) as u8);
-                    } else {
-                        println!("Song recognized: {} - {}", song.song_name, song.artist_name);
-                    }
-                    update_presence(client2.lock().await, &song).await;
-                }
-                Err(e) => {
-                    println!("Error: {}", e);
-                    client2.lock().await.discord.clear_activity().await.unwrap();
-                }
-            }
+        if let Err(e) = record_audio(producer, input_clock, &device, &stream_config, input_events.clone()).await {
+            let _ = input_events.send(Event::InputLost(e.to_string()));
         }
     });
 
+    listener.start(consumer, clock);
+
     println!("Recording audio in {}s intervals... Press Ctrl+C to stop.", seconds_per_read);
 
     signal::ctrl_c().await.expect("Failed to listen for event");
@@ -151,84 +161,89 @@ async fn main() {
     println!("Received Ctrl+C event. Shutting down...");
 
     rec_thread.abort();
-    req_thread.abort();
+    listener.stop();
 
-    let client_lock = client.lock().await;
-
-    match client_lock.discord.clear_activity().await {
-        Ok(_) => {
-            println!("Cleared discord activity");
-        }
-        Err(e) => {
-            eprintln!("[DISCORD] Failed to clear discord activity: {}", e);
-        }
+    // Give sinks a moment to clean up, e.g. clear the Discord activity
+    let _ = listener.events().send(Event::Shutdown);
+    for sink in sinks {
+        let _ = tokio::time::timeout(Duration::from_secs(2), sink).await;
     }
 
     exit(0);
 }
 
-async fn record_audio(
-    mut producer: Caching<Arc<SharedRb<Heap<i16>>>, true, false>,
-    device: &Device,
-    config: &StreamConfig,
-) -> anyhow::Result<()> {
-    let sample_rate = config.sample_rate.0;
-    let input_data_fn = move |data: &[i16], _: &cpal::InputCallbackInfo| {
-        // let mut output_fell_behind = false;
-        let data = samples_to_16khz(stereo_pcm_to_mono(data), sample_rate);
-        if producer.push_slice(&data) == 0 {
-            // output_fell_behind = true;
-        }
-        // if output_fell_behind {
-        //     eprintln!("output stream fell behind: try increasing latency");
-        // }
-    };
-
-    let err_fn = |err: cpal::StreamError| {
-        eprintln!("an error occurred on stream: {}", err);
-    };
-
-    let input_stream = device.build_input_stream(&config, input_data_fn, err_fn, None)?;
-
-    input_stream.play()?;
-
-    loop {
-        thread::sleep(std::time::Duration::from_secs(1));
-    }
-    // drop(input_stream);
-
-    // Ok(())
+fn recognizer(config: &Config) -> Recognizer {
+    Recognizer::new(config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    })
 }
 
-fn stereo_pcm_to_mono(pcm: &[i16]) -> Vec<i16> {
-    let mut mono = Vec::with_capacity(pcm.len() / 2);
-
-    for i in (0..pcm.len()).step_by(2) {
-        let sample = (pcm[i] as i32 + pcm[i + 1] as i32) / 2;
-        mono.push(sample as i16);
+async fn search(recognizer: &Recognizer, term: &str) {
+    match recognizer.search_tracks(term, 10).await {
+        Ok(tracks) if tracks.is_empty() => println!("No results for \"{}\"", term),
+        Ok(tracks) => {
+            for track in tracks {
+                println!("{}: {} - {}", track.key, track.title, track.subtitle);
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
     }
-
-    mono
 }
 
-fn samples_to_16khz(samples: Vec<i16>, in_sample_rate: u32) -> Vec<i16> {
-    if in_sample_rate == 16_000 {
-        return samples;
-    }
+/// Recognises a file window by window, e.g. every song in a concert video.
+#[cfg(feature = "ffmpeg")]
+async fn recognize_file(recognizer: &Recognizer, path: &str, window_secs: u32) {
+    use song_id::shazam::error::RecognitionError;
+    use song_id::{try_recognize_song, SignatureGenerator};
+
+    // Decoding and fingerprinting a whole file takes a while, keep it off the runtime's threads
+    let file = std::path::PathBuf::from(path);
+    let decoded = tokio::task::spawn_blocking(move || SignatureGenerator::make_signatures_from_file(&file, window_secs)).await;
+    let signatures = match decoded.expect("fingerprinting thread panicked") {
+        Ok(signatures) => signatures,
+        Err(e) => {
+            eprintln!("Error decoding {}: {}", path, e);
+            return;
+        }
+    };
 
-    if in_sample_rate % 16_000 != 0 {
-        panic!("The input sample rate must be a multiple of 16_000");
+    println!("{}:", path);
+    let mut last_track_key = None;
+    for (i, signature) in signatures.into_iter().enumerate() {
+        let offset = i as u32 * window_secs;
+        match try_recognize_song(recognizer, signature, std::time::SystemTime::now()).await {
+            Ok(song) if last_track_key.as_ref() == Some(&song.track_key) => {}
+            Ok(song) => {
+                println!("  {}:{:02} {} - {}", offset / 60, offset % 60, song.song_name, song.artist_name);
+                last_track_key = Some(song.track_key);
+            }
+            Err(RecognitionError::NoMatch { .. }) => last_track_key = None,
+            Err(e) => eprintln!("  {}:{:02} Error: {}", offset / 60, offset % 60, e),
+        }
     }
+}
 
-    let samples_to_merge = (in_sample_rate / 16_000) as usize;
-
-    let mut res = Vec::with_capacity(samples.len() / samples_to_merge);
-    for i in (0..samples.len()).step_by(samples_to_merge) {
-        let mut sum: i32 = 0;
-        for j in 0..samples_to_merge {
-            sum += samples[i + j] as i32;
+async fn lookup(recognizer: &Recognizer, cache: &mut TrackCache, track_key: &str) {
+    match refresh_cached_track(recognizer, cache, track_key).await {
+        Ok(track) => {
+            println!("{}: {} - {}", track_key, track.song_name, track.artist_name);
+            for (name, value) in [
+                ("Album", &track.album_name),
+                ("Released", &track.release_year),
+                ("Genre", &track.genre),
+                ("Label", &track.label),
+                ("ISRC", &track.isrc),
+                ("Link", &track.share_url),
+            ] {
+                if let Some(value) = value {
+                    println!("  {}: {}", name, value);
+                }
+            }
         }
-        res.push((sum / samples_to_merge as i32) as i16)
+        Err(e) => eprintln!("Error looking up {}: {}", track_key, e),
     }
-    res
 }
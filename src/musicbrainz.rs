@@ -0,0 +1,284 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use reqwest::header::USER_AGENT;
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::config::Config;
+use crate::shazam::core::thread_messages::{MusicBrainzInfo, SongRecognizedMessage};
+
+/// Looks recognised tracks up in MusicBrainz, at most one request per `min_interval`
+/// as their rate limiting rules ask.
+pub struct MusicBrainz {
+    client: Client,
+    base_url: String,
+    user_agent: String,
+    min_interval: Duration,
+    last_request: Option<Instant>,
+    /// Tracks we already looked for and didn't find, so we don't ask again every window.
+    misses: HashSet<String>,
+}
+
+#[derive(Deserialize)]
+struct RecordingSearch {
+    recordings: Vec<Recording>,
+}
+
+#[derive(Deserialize)]
+struct Recording {
+    id: String,
+    /// In milliseconds.
+    length: Option<u64>,
+    #[serde(default)]
+    releases: Vec<Release>,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    id: String,
+    title: String,
+    date: Option<String>,
+    #[serde(default)]
+    media: Vec<Medium>,
+}
+
+#[derive(Deserialize)]
+struct Medium {
+    position: Option<u32>,
+    format: Option<String>,
+    /// Only the matching track in search results.
+    #[serde(default, rename = "track")]
+    tracks: Vec<ReleaseTrack>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseTrack {
+    number: String,
+    length: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseLabels {
+    #[serde(default, rename = "label-info")]
+    label_info: Vec<LabelInfo>,
+}
+
+#[derive(Deserialize)]
+struct LabelInfo {
+    label: Option<Label>,
+}
+
+#[derive(Deserialize)]
+struct Label {
+    name: String,
+}
+
+impl MusicBrainz {
+    pub fn new(config: &Config, client: Client) -> MusicBrainz {
+        MusicBrainz {
+            client,
+            base_url: config.musicbrainz.base_url.trim_end_matches('/').to_string(),
+            user_agent: config.musicbrainz.user_agent.clone(),
+            min_interval: Duration::from_millis(config.musicbrainz.min_interval_ms),
+            last_request: None,
+            misses: HashSet::new(),
+        }
+    }
+
+    /// Finds the recording by ISRC, or by artist and title if that fails, and
+    /// picks the release matching the album name if there is one.
+    pub async fn lookup(&mut self, song: &SongRecognizedMessage) -> Result<Option<MusicBrainzInfo>, String> {
+        if self.misses.contains(&song.track_key) {
+            return Ok(None);
+        }
+
+        let mut recordings = vec![];
+        if let Some(isrc) = &song.isrc {
+            recordings = self.search(&format!("isrc:{}", escape(isrc))).await?;
+        }
+        if recordings.is_empty() {
+            recordings = self.search(&format!("recording:\"{}\" AND artist:\"{}\"", escape(&song.song_name), escape(&song.artist_name))).await?;
+        }
+
+        let recording = match recordings.into_iter().find(|recording| !recording.releases.is_empty()) {
+            Some(recording) => recording,
+            None => {
+                self.misses.insert(song.track_key.clone());
+                return Ok(None);
+            }
+        };
+
+        let album_name = song.album_name.as_deref().map(str::to_lowercase);
+        let mut releases = recording.releases;
+        let index = releases.iter()
+            .position(|release| Some(release.title.to_lowercase()) == album_name)
+            .unwrap_or(0);
+        let release = releases.swap_remove(index);
+
+        let labels: ReleaseLabels = self.get(&format!("{}/release/{}", self.base_url, release.id), &[("inc", "labels")]).await?;
+        let medium = release.media.first();
+        let track = medium.and_then(|medium| medium.tracks.first());
+
+        Ok(Some(MusicBrainzInfo {
+            recording_id: recording.id,
+            release_id: release.id,
+            track_number: track.map(|track| track.number.clone()),
+            disc_number: medium.and_then(|medium| medium.position),
+            medium_format: medium.and_then(|medium| medium.format.clone()),
+            release_date: release.date,
+            length_ms: track.and_then(|track| track.length).or(recording.length),
+            label: labels.label_info.into_iter().find_map(|info| info.label).map(|label| label.name),
+        }))
+    }
+
+    async fn search(&mut self, query: &str) -> Result<Vec<Recording>, String> {
+        let search: RecordingSearch = self.get(&format!("{}/recording", self.base_url), &[("query", query), ("limit", "5")]).await?;
+        Ok(search.recordings)
+    }
+
+    async fn get<T: DeserializeOwned>(&mut self, url: &str, query: &[(&str, &str)]) -> Result<T, String> {
+        if let Some(last_request) = self.last_request {
+            tokio::time::sleep_until(last_request + self.min_interval).await;
+        }
+        self.last_request = Some(Instant::now());
+
+        self.client.get(url)
+            .query(query)
+            .query(&[("fmt", "json")])
+            .header(USER_AGENT, &self.user_agent)
+            .send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Request failed: {}", e))?
+            .json().await
+            .map_err(|e| format!("Unexpected response: {}", e))
+    }
+}
+
+/// Escapes Lucene special characters for use in a search query.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "+-&|!(){}[]^\"~*?:\\/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::testing::{message, song, Stub, SONG_A};
+
+    const RECORDINGS: &str = r#"{
+        "recordings": [{
+            "id": "5f1b5e0c-3d2a-4c8e-9a51-7f0e2c6d4b11",
+            "length": 215000,
+            "releases": [
+                {"id": "a0c3e1d2-0000-4000-8000-000000000001", "title": "Harbour Singles", "date": "2021-06-11", "media": []},
+                {
+                    "id": "a0c3e1d2-0000-4000-8000-000000000002",
+                    "title": "Low Tide Letters",
+                    "date": "2021-09-03",
+                    "media": [{"position": 1, "format": "12\" Vinyl", "track": [{"number": "A3", "length": 214000}]}]
+                }
+            ]
+        }]
+    }"#;
+
+    const LABELS: &str = r#"{"label-info": [{"label": null}, {"label": {"name": "Driftwood Records"}}]}"#;
+
+    fn musicbrainz(stub: &Stub, min_interval_ms: u64) -> MusicBrainz {
+        let mut config = Config::default();
+        config.musicbrainz.base_url = format!("{}/", stub.url);
+        config.musicbrainz.min_interval_ms = min_interval_ms;
+        MusicBrainz::new(&config, Client::new())
+    }
+
+    fn paper_lanterns() -> SongRecognizedMessage {
+        let mut song = message("612846392", &song(SONG_A, 0, 1, 1));
+        song.song_name = "Paper Lanterns".to_string();
+        song.artist_name = "The Quiet Harbour".to_string();
+        song.album_name = Some("Low Tide Letters".to_string());
+        song.isrc = Some("GBXYZ2100017".to_string());
+        song
+    }
+
+    #[tokio::test]
+    async fn finds_recordings_by_isrc() {
+        let stub = Stub::start(|request| match request.target.starts_with("/release/") {
+            true => (200, LABELS.to_string()),
+            false => (200, RECORDINGS.to_string()),
+        })
+        .await;
+
+        let info = musicbrainz(&stub, 0).lookup(&paper_lanterns()).await.unwrap().unwrap();
+        assert_eq!(info.recording_id, "5f1b5e0c-3d2a-4c8e-9a51-7f0e2c6d4b11");
+        // The release named like the album, not the first one
+        assert_eq!(info.release_id, "a0c3e1d2-0000-4000-8000-000000000002");
+        assert_eq!(info.release_date.as_deref(), Some("2021-09-03"));
+        assert_eq!(info.track_number.as_deref(), Some("A3"));
+        assert_eq!(info.disc_number, Some(1));
+        assert_eq!(info.medium_format.as_deref(), Some("12\" Vinyl"));
+        assert_eq!(info.length_ms, Some(214000));
+        assert_eq!(info.label.as_deref(), Some("Driftwood Records"));
+
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].target.starts_with("/recording?query=isrc%3AGBXYZ2100017&limit=5&fmt=json"), "{}", requests[0].target);
+        assert_eq!(requests[1].target, "/release/a0c3e1d2-0000-4000-8000-000000000002?inc=labels&fmt=json");
+        assert!(requests.iter().all(|request| request.header("user-agent").is_some_and(|agent| agent.starts_with("song_id/"))));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_artist_and_title() {
+        let stub = Stub::start(|request| match request.target.as_str() {
+            target if target.contains("isrc%3A") => (200, r#"{"recordings": []}"#.to_string()),
+            target if target.starts_with("/release/") => (200, LABELS.to_string()),
+            _ => (200, RECORDINGS.to_string()),
+        })
+        .await;
+
+        let info = musicbrainz(&stub, 0).lookup(&paper_lanterns()).await.unwrap().unwrap();
+        assert_eq!(info.track_number.as_deref(), Some("A3"));
+        assert_eq!(info.label.as_deref(), Some("Driftwood Records"));
+
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].target.contains("query=recording%3A%22Paper+Lanterns%22+AND+artist%3A%22The+Quiet+Harbour%22"), "{}", requests[1].target);
+    }
+
+    #[tokio::test]
+    async fn spaces_requests_out() {
+        let stub = Stub::start(|request| match request.target.starts_with("/release/") {
+            true => (200, LABELS.to_string()),
+            false => (200, RECORDINGS.to_string()),
+        })
+        .await;
+        let mut musicbrainz = musicbrainz(&stub, 150);
+
+        // Two requests each, the first one of the second lookup waits for the last one of the first
+        let started = Instant::now();
+        musicbrainz.lookup(&paper_lanterns()).await.unwrap().unwrap();
+        musicbrainz.lookup(&paper_lanterns()).await.unwrap().unwrap();
+        assert_eq!(stub.requests().len(), 4);
+        assert!(started.elapsed() >= Duration::from_millis(3 * 150), "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn remembers_misses() {
+        let stub = Stub::start(|_| (200, r#"{"recordings": []}"#.to_string())).await;
+        let mut musicbrainz = musicbrainz(&stub, 0);
+
+        assert!(musicbrainz.lookup(&paper_lanterns()).await.unwrap().is_none());
+        assert!(musicbrainz.lookup(&paper_lanterns()).await.unwrap().is_none());
+        // ISRC, then artist and title, and only the once
+        assert_eq!(stub.requests().len(), 2);
+    }
+}
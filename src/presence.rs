@@ -1,90 +1,216 @@
+use std::future::Future;
+use std::time::Duration;
+
 use discord_sdk as ds;
 use ds::activity::{self, Activity, ActivityArgs, Assets, Button, ButtonKind, IntoTimestamp, Timestamps};
 use tokio::sync::MutexGuard;
 
-pub const APP_ID: ds::AppId = 1236161402050183238;
+use crate::config::{DiscordButton, DiscordConfig};
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+
+/// Requests to Discord can hang if it goes away mid-request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Discord's limits: bytes for text fields and button URLs, characters for button labels.
+const MAX_TEXT_BYTES: usize = 128;
+const MAX_URL_BYTES: usize = 512;
+const MAX_LABEL_CHARS: usize = 32;
 
 pub struct Client {
     pub discord: ds::Discord,
-    pub user: ds::user::User,
     pub wheel: ds::wheel::Wheel,
 }
 
-pub async fn make_client(subs: ds::Subscriptions) -> Client {
+impl Client {
+    pub fn is_connected(&self) -> bool {
+        matches!(*self.wheel.user().0.borrow(), ds::wheel::UserState::Connected(_))
+    }
+}
+
+/// Starts connecting to Discord in the background. Doesn't wait for the
+/// handshake: the SDK keeps retrying for as long as Discord isn't running,
+/// and reconnects by itself when it restarts. Watch `wheel.user()` to find out when.
+pub fn make_client(app_id: ds::AppId, subs: ds::Subscriptions) -> Result<Client, String> {
     let (wheel, handler) = ds::wheel::Wheel::new(Box::new(|err| {
         eprintln!("[DISCORD] Encountered an error: {err}");
     }));
 
-    let mut user = wheel.user();
+    let discord = ds::Discord::new(ds::DiscordApp::PlainId(app_id), subs, Box::new(handler))
+        .map_err(|e| format!("Unable to create discord client: {}", e))?;
 
-    let discord = ds::Discord::new(ds::DiscordApp::PlainId(APP_ID), subs, Box::new(handler))
-        .expect("[DISCORD] Unable to create discord client");
-
-    println!("[DISCORD] Waiting for handshake...");
-    user.0.changed().await.unwrap();
-
-    let user = match &*user.0.borrow() {
-        ds::wheel::UserState::Connected(user) => user.clone(),
-        ds::wheel::UserState::Disconnected(err) => panic!("failed to connect to Discord: {}", err),
-    };
-
-    println!(
-        "[DISCORD] Connected to Discord, local user is {}",
-        user.username
-    );
-
-    Client {
+    Ok(Client {
         discord,
-        user,
         wheel,
+    })
+}
+
+/// Clears the activity, if connected.
+pub async fn clear_presence(client: MutexGuard<'_, Client>) -> Result<(), String> {
+    if !client.is_connected() {
+        return Ok(());
     }
+
+    request(client.discord.clear_activity()).await
 }
 
 pub async fn update_presence(
     client: MutexGuard<'_, Client>,
-    song: &crate::shazam::core::thread_messages::SongRecognizedMessage,
-) {
-    // let button: ButtonKind = ButtonKind::Link(Button {
-    //     label: "View GitHub".to_string(),
-    //     url: "https://github.com/barnabwhy/song_id".to_string(),
-    // });
+    config: &DiscordConfig,
+    song: &SongRecognizedMessage,
+) -> Result<(), String> {
+    if !client.is_connected() {
+        return Ok(());
+    }
+
+    let buttons: Vec<ButtonKind> = config
+        .buttons
+        .iter()
+        .filter_map(|button| render_button(button, song).map(ButtonKind::Link))
+        .take(2)
+        .collect();
 
     let mut activity = Activity {
-        state: Some(song.artist_name.to_string()),
-        details: Some(song.song_name.to_string()),
+        state: render(&config.state, song),
+        details: render(&config.details, song),
         assets: None,
         timestamps: None,
         party: None,
-        // buttons_or_secrets: Some(activity::ButtonsOrSecrets::Buttons {
-        //     buttons: vec![button],
-        // }),
-        buttons_or_secrets: None,
+        buttons_or_secrets: (!buttons.is_empty()).then_some(activity::ButtonsOrSecrets::Buttons { buttons }),
         kind: ds::activity::ActivityKind::Listening,
         instance: false,
     };
 
+    let mut assets = Assets::default();
     if let Some(cover_image) = &song.cover_image {
-        activity.assets = Some(Assets::default().large(cover_image, song.album_name.to_owned()));
+        assets = assets.large(cover_image, render(&config.large_text, song));
+    }
+    if let Some(small_image) = &config.small_image {
+        assets = assets.small(small_image, render(&config.small_text, song));
+    }
+    if assets.large_image.is_some() || assets.small_image.is_some() {
+        activity.assets = Some(assets);
     }
 
-    if let Some(seek) = song.track_seek {
-        let timestamp = song.timestamp.into_timestamp()
-            - seek as i64
-            - (song.signature.number_samples as i64 / song.signature.sample_rate_hz as i64);
+    // With both, Discord shows a progress bar instead of the elapsed time
+    if let Some(started_at) = song.track_started_at() {
         activity.timestamps = Some(Timestamps {
-            start: Some(timestamp),
-            end: None,
+            start: Some(started_at.into_timestamp()),
+            end: song.track_ends_at().map(IntoTimestamp::into_timestamp),
         });
     }
 
     let mut activity_args = ActivityArgs::default();
     activity_args.activity = Some(activity);
 
-    client
-        .discord
-        .update_activity(activity_args)
-        .await
-        .expect("[DISCORD] Failed to update presence");
+    request(client.discord.update_activity(activity_args)).await?;
 
     println!("[DISCORD] Updated presence");
+    Ok(())
+}
+
+/// Fills in a template from `DiscordConfig`. `None` if it comes out empty,
+/// since Discord rejects empty (and one character) strings.
+fn render(template: &str, song: &SongRecognizedMessage) -> Option<String> {
+    let text = fill(template, song);
+    if text.len() < 2 {
+        return None;
+    }
+
+    let mut end = text.len().min(MAX_TEXT_BYTES);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(text[..end].to_string())
+}
+
+/// A cut-off URL would just be a broken link, so buttons with URLs that are
+/// too long are left out rather than truncated.
+fn render_button(button: &DiscordButton, song: &SongRecognizedMessage) -> Option<Button> {
+    let label = fill(&button.label, song);
+    let url = fill(&button.url, song);
+    if label.chars().count() < 2 || url.is_empty() || url.len() > MAX_URL_BYTES {
+        return None;
+    }
+
+    Some(Button {
+        label: label.chars().take(MAX_LABEL_CHARS).collect(),
+        url,
+    })
+}
+
+fn fill(template: &str, song: &SongRecognizedMessage) -> String {
+    let musicbrainz = song.musicbrainz.as_ref();
+    let fields: [(&str, Option<String>); 17] = [
+        ("song", Some(song.song_name.clone())),
+        ("artist", Some(song.artist_name.clone())),
+        ("album", song.album_name.clone()),
+        ("year", song.release_year.clone()),
+        ("genre", song.genre.clone()),
+        ("label", song.label.clone().or_else(|| musicbrainz.and_then(|mb| mb.label.clone()))),
+        ("isrc", song.isrc.clone()),
+        ("track_key", Some(song.track_key.clone())),
+        ("share_url", song.share_url.clone()),
+        ("apple_music", song.links.apple_music.clone()),
+        ("spotify", song.links.spotify.clone()),
+        ("youtube_music", song.links.youtube_music.clone()),
+        ("confidence", song.confidence().map(|confidence| format!("{:.0}%", confidence * 100.0))),
+        ("track_number", musicbrainz.and_then(|mb| mb.track_number.clone())),
+        ("disc_number", musicbrainz.and_then(|mb| mb.disc_number).map(|disc| disc.to_string())),
+        ("medium_format", musicbrainz.and_then(|mb| mb.medium_format.clone())),
+        ("release_date", musicbrainz.and_then(|mb| mb.release_date.clone())),
+    ];
+
+    let mut text = template.to_string();
+    for (name, value) in fields {
+        text = text.replace(&format!("{{{}}}", name), value.as_deref().unwrap_or(""));
+    }
+
+    text.trim().to_string()
+}
+
+async fn request<T>(request: impl Future<Output = Result<T, ds::Error>>) -> Result<(), String> {
+    match tokio::time::timeout(REQUEST_TIMEOUT, request).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("Discord didn't respond".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{message, song, SONG_A};
+
+    fn button(label: &str, url: &str) -> DiscordButton {
+        DiscordButton { label: label.to_string(), url: url.to_string() }
+    }
+
+    #[test]
+    fn keeps_long_urls_whole() {
+        let mut song = message("612846392", &song(SONG_A, 0, 1, 1));
+        song.share_url = Some(format!("https://www.shazam.com/track/612846392?{}", "a".repeat(200)));
+
+        let rendered = render_button(&button("Open in Shazam", "{share_url}"), &song).unwrap();
+        assert_eq!(Some(rendered.url), song.share_url);
+    }
+
+    #[test]
+    fn skips_buttons_with_urls_over_the_limit() {
+        let mut song = message("612846392", &song(SONG_A, 0, 1, 1));
+        song.share_url = Some(format!("https://www.shazam.com/track/612846392?{}", "a".repeat(MAX_URL_BYTES)));
+        assert!(render_button(&button("Open in Shazam", "{share_url}"), &song).is_none());
+
+        song.share_url = None;
+        assert!(render_button(&button("Open in Shazam", "{share_url}"), &song).is_none());
+    }
+
+    #[test]
+    fn cuts_labels_at_32_characters() {
+        let mut song = message("612846392", &song(SONG_A, 0, 1, 1));
+        song.song_name = "Ça plane pour moi, ça plane pour moi, ça plane".to_string();
+
+        let rendered = render_button(&button("{song}", "https://example.com"), &song).unwrap();
+        assert_eq!(rendered.label, "Ça plane pour moi, ça plane pour");
+        assert_eq!(rendered.label.chars().count(), 32);
+    }
 }
@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use md5::{Digest, Md5};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+use crate::shazam::fingerprinting::communication::build_client;
+use crate::utils::{load_json, save_json};
+
+const QUEUE_FILE_NAME: &str = "scrobble_queue.json";
+/// Most scrobbles the API takes in one request.
+const BATCH_SIZE: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Scrobble {
+    pub artist: String,
+    pub track: String,
+    pub album: Option<String>,
+    /// Unix time the track started playing.
+    pub timestamp: u64,
+    pub duration_secs: Option<u64>,
+}
+
+impl Scrobble {
+    pub fn new(song: &SongRecognizedMessage, started_at: SystemTime) -> Scrobble {
+        Scrobble {
+            artist: song.artist_name.clone(),
+            track: song.song_name.clone(),
+            album: song.album_name.clone(),
+            timestamp: started_at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs(),
+            duration_secs: song.duration.map(|duration| duration.as_secs()),
+        }
+    }
+}
+
+/// Talks to Last.fm (or a compatible API). Scrobbles are kept on disk until
+/// they've been accepted, so nothing is lost while offline.
+pub struct Scrobbler {
+    client: Client,
+    api_root: String,
+    api_key: String,
+    api_secret: String,
+    session_key: String,
+    path: PathBuf,
+    queue: Vec<Scrobble>,
+}
+
+impl Scrobbler {
+    pub fn new(config: &Config) -> Result<Scrobbler, String> {
+        let scrobbler = config.scrobbler.as_ref().ok_or("No `scrobbler` section in the config")?;
+        let path = config.data_dir.join(QUEUE_FILE_NAME);
+
+        let queue: Vec<Scrobble> = load_json(&path, "SCROBBLER");
+
+        if !queue.is_empty() {
+            println!("[SCROBBLER] {} scrobbles waiting to be sent", queue.len());
+        }
+
+        Ok(Scrobbler {
+            client: build_client(&config.http)?,
+            api_root: scrobbler.api_root.clone(),
+            api_key: scrobbler.api_key.clone(),
+            api_secret: scrobbler.api_secret.clone(),
+            session_key: scrobbler.session_key.clone(),
+            path,
+            queue,
+        })
+    }
+
+    pub async fn now_playing(&self, song: &SongRecognizedMessage) -> Result<(), ApiError> {
+        let mut params = vec![
+            ("artist".to_string(), song.artist_name.clone()),
+            ("track".to_string(), song.song_name.clone()),
+        ];
+        if let Some(album) = &song.album_name {
+            params.push(("album".to_string(), album.clone()));
+        }
+        if let Some(duration) = song.duration {
+            params.push(("duration".to_string(), duration.as_secs().to_string()));
+        }
+
+        self.call("track.updateNowPlaying", params).await
+    }
+
+    /// Queues the scrobble and sends everything in the queue.
+    pub async fn scrobble(&mut self, scrobble: Scrobble) {
+        self.queue.push(scrobble);
+        self.save();
+        self.flush().await;
+    }
+
+    /// Sends whatever is queued, oldest first. Stops at the first failure.
+    pub async fn flush(&mut self) {
+        // How many to send one by one, after a batch was refused because of one of them
+        let mut singles = 0;
+
+        while !self.queue.is_empty() {
+            let batch_size = if singles > 0 { 1 } else { BATCH_SIZE };
+            let batch = &self.queue[..self.queue.len().min(batch_size)];
+
+            let mut params = vec![];
+            for (i, scrobble) in batch.iter().enumerate() {
+                params.push((format!("artist[{}]", i), scrobble.artist.clone()));
+                params.push((format!("track[{}]", i), scrobble.track.clone()));
+                params.push((format!("timestamp[{}]", i), scrobble.timestamp.to_string()));
+                if let Some(album) = &scrobble.album {
+                    params.push((format!("album[{}]", i), album.clone()));
+                }
+                if let Some(duration) = scrobble.duration_secs {
+                    params.push((format!("duration[{}]", i), duration.to_string()));
+                }
+            }
+
+            let sent = batch.len();
+            match self.call("track.scrobble", params).await {
+                Ok(_) => {}
+                Err(ApiError::Rejected(6, _)) if sent > 1 => {
+                    // Invalid parameters, but only the bad scrobble should be dropped
+                    singles = sent;
+                    continue;
+                }
+                Err(ApiError::Rejected(6, message)) => {
+                    // Retrying won't help
+                    eprintln!("[SCROBBLER] Dropping {} - {}, the server refused it: {}", batch[0].artist, batch[0].track, message);
+                }
+                Err(e) => {
+                    eprintln!("[SCROBBLER] Failed to submit {} scrobbles, will retry later: {}", self.queue.len(), e);
+                    return;
+                }
+            }
+
+            self.queue.drain(..sent);
+            self.save();
+            singles = singles.saturating_sub(sent);
+        }
+    }
+
+    async fn call(&self, method: &str, params: Vec<(String, String)>) -> Result<(), ApiError> {
+        let mut params: BTreeMap<String, String> = params.into_iter().collect();
+        params.insert("method".to_string(), method.to_string());
+        params.insert("api_key".to_string(), self.api_key.clone());
+        params.insert("sk".to_string(), self.session_key.clone());
+
+        // Every parameter sorted by name, then the secret
+        let mut signature = Md5::new();
+        for (name, value) in &params {
+            signature.update(name);
+            signature.update(value);
+        }
+        signature.update(&self.api_secret);
+        let signature: String = signature.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        params.insert("api_sig".to_string(), signature);
+        params.insert("format".to_string(), "json".to_string());
+
+        let response = self.client.post(&self.api_root).form(&params).send().await.map_err(|e| ApiError::Network(e.to_string()))?;
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+
+        if let Some(code) = body.get("error").and_then(Value::as_u64) {
+            let message = body.get("message").and_then(Value::as_str).unwrap_or_default();
+            return Err(ApiError::Rejected(code, message.to_string()));
+        }
+        if !status.is_success() {
+            return Err(ApiError::Network(format!("HTTP {}", status)));
+        }
+
+        Ok(())
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &self.queue, "SCROBBLER");
+    }
+}
+
+pub enum ApiError {
+    Network(String),
+    /// Error code and message from the API.
+    Rejected(u64, String),
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Network(message) => write!(f, "{}", message),
+            ApiError::Rejected(code, message) => write!(f, "error {}: {}", code, message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config::ScrobblerConfig;
+    use crate::testing::{message, song, temp_dir, Request, Stub, SONG_A};
+
+    fn config(stub: &Stub, name: &str) -> Config {
+        Config {
+            data_dir: temp_dir(name),
+            scrobbler: Some(ScrobblerConfig {
+                api_key: "test-key".to_string(),
+                api_secret: "test-secret".to_string(),
+                session_key: "test-session".to_string(),
+                api_root: format!("{}/2.0/", stub.url),
+            }),
+            ..Config::default()
+        }
+    }
+
+    fn param<'a>(request: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        request.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn scrobble(i: u64) -> Scrobble {
+        Scrobble {
+            artist: format!("Artist {}", i),
+            track: format!("Track {}", i),
+            album: None,
+            timestamp: 1_700_000_000 + i * 200,
+            duration_secs: Some(200),
+        }
+    }
+
+    fn queued(config: &Config) -> Vec<Scrobble> {
+        load_json(&config.data_dir.join(QUEUE_FILE_NAME), "SCROBBLER")
+    }
+
+    #[tokio::test]
+    async fn signs_requests() {
+        let stub = Stub::start(|_| (200, "{}".to_string())).await;
+        let scrobbler = Scrobbler::new(&config(&stub, "scrobbler_sign")).unwrap();
+
+        assert!(scrobbler.now_playing(&message("612846392", &song(SONG_A, 0, 1, 1))).await.is_ok());
+
+        let requests = stub.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].target, "/2.0/");
+        let form = requests[0].form();
+        assert_eq!(param(&form, "method"), Some("track.updateNowPlaying"));
+        assert_eq!(param(&form, "artist"), Some("Artist of 612846392"));
+        assert_eq!(param(&form, "sk"), Some("test-session"));
+        assert_eq!(param(&form, "format"), Some("json"));
+        // md5 of every parameter but `format` and itself, sorted, then the
+        // secret. Worked out separately with Python's hashlib.
+        assert_eq!(param(&form, "api_sig"), Some("018a176f8443a306bdf61b2a5386da98"));
+    }
+
+    #[tokio::test]
+    async fn sends_in_batches() {
+        let stub = Stub::start(|_| (200, r#"{"scrobbles": {}}"#.to_string())).await;
+        let config = config(&stub, "scrobbler_batches");
+        save_json(&config.data_dir.join(QUEUE_FILE_NAME), &(0..120).map(scrobble).collect::<Vec<_>>(), "SCROBBLER");
+
+        let mut scrobbler = Scrobbler::new(&config).unwrap();
+        scrobbler.flush().await;
+
+        let requests: Vec<_> = stub.requests().iter().map(Request::form).collect();
+        let sizes: Vec<_> = requests.iter().map(|form| form.iter().filter(|(key, _)| key.starts_with("artist[")).count()).collect();
+        assert_eq!(sizes, [50, 50, 20]);
+
+        // Oldest first, numbered from 0 in each batch
+        assert_eq!(param(&requests[0], "artist[0]"), Some("Artist 0"));
+        assert_eq!(param(&requests[1], "artist[0]"), Some("Artist 50"));
+        assert_eq!(param(&requests[2], "track[19]"), Some("Track 119"));
+        assert_eq!(param(&requests[2], "timestamp[19]"), Some("1700023800"));
+        assert_eq!(param(&requests[2], "duration[19]"), Some("200"));
+        assert_eq!(param(&requests[2], "method"), Some("track.scrobble"));
+
+        assert!(queued(&config).is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_failed_scrobbles_on_disk() {
+        let up = Arc::new(AtomicBool::new(false));
+        let server_up = up.clone();
+        let stub = Stub::start(move |_| match server_up.load(Ordering::SeqCst) {
+            true => (200, "{}".to_string()),
+            false => (503, r#"{"error": 16, "message": "Service temporarily unavailable"}"#.to_string()),
+        })
+        .await;
+        let config = config(&stub, "scrobbler_retry");
+
+        let mut scrobbler = Scrobbler::new(&config).unwrap();
+        scrobbler.scrobble(scrobble(1)).await;
+        scrobbler.scrobble(scrobble(2)).await;
+        assert_eq!(stub.requests().len(), 2);
+        assert_eq!(queued(&config).iter().map(|scrobble| scrobble.track.as_str()).collect::<Vec<_>>(), ["Track 1", "Track 2"]);
+
+        // Picked up again after a restart
+        up.store(true, Ordering::SeqCst);
+        Scrobbler::new(&config).unwrap().flush().await;
+        assert!(queued(&config).is_empty());
+
+        let form = stub.requests()[2].form();
+        assert_eq!(param(&form, "track[0]"), Some("Track 1"));
+        assert_eq!(param(&form, "track[1]"), Some("Track 2"));
+    }
+
+    #[tokio::test]
+    async fn drops_scrobbles_the_server_refuses() {
+        let stub = Stub::start(|_| (400, r#"{"error": 6, "message": "Invalid parameters"}"#.to_string())).await;
+        let config = config(&stub, "scrobbler_refused");
+
+        Scrobbler::new(&config).unwrap().scrobble(scrobble(1)).await;
+        assert!(queued(&config).is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_drops_the_scrobble_the_server_refuses() {
+        let stub = Stub::start(|request| match request.form().iter().any(|(_, value)| value == "Track 1") {
+            true => (400, r#"{"error": 6, "message": "Invalid parameters"}"#.to_string()),
+            false => (200, "{}".to_string()),
+        })
+        .await;
+        let config = config(&stub, "scrobbler_refused_one");
+        save_json(&config.data_dir.join(QUEUE_FILE_NAME), &(0..55).map(scrobble).collect::<Vec<_>>(), "SCROBBLER");
+
+        Scrobbler::new(&config).unwrap().flush().await;
+        assert!(queued(&config).is_empty());
+
+        // The refused batch again one at a time, then back to batches
+        let requests: Vec<_> = stub.requests().iter().map(Request::form).collect();
+        let sizes: Vec<_> = requests.iter().map(|form| form.iter().filter(|(key, _)| key.starts_with("artist[")).count()).collect();
+        assert_eq!(sizes, [vec![50], vec![1; 50], vec![5]].concat());
+        assert_eq!(param(&requests[1], "track[0]"), Some("Track 0"));
+        assert_eq!(param(&requests[2], "track[0]"), Some("Track 1"));
+        assert_eq!(param(&requests[51], "track[0]"), Some("Track 50"));
+    }
+}
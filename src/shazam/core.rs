@@ -1,2 +1,13 @@
+#[cfg(feature = "shazam-client")]
+pub mod album;
+#[cfg(feature = "shazam-client")]
+pub mod budget;
+#[cfg(feature = "local-db")]
+pub mod cache;
+#[cfg(feature = "shazam-client")]
 pub mod http;
-pub mod thread_messages;
\ No newline at end of file
+#[cfg(all(feature = "shazam-client", feature = "local-db"))]
+pub mod offline_queue;
+#[cfg(feature = "shazam-client")]
+pub mod response;
+pub mod thread_messages;
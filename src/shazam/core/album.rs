@@ -0,0 +1,342 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+use crate::shazam::error::RecognitionError;
+use crate::shazam::fingerprinting::communication::Recognizer;
+use crate::utils::{load_json, save_json};
+
+const ALBUM_CACHE_FILE_NAME: &str = "album_cache.json";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Album {
+    pub name: String,
+    pub tracks: Vec<AlbumTrack>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AlbumTrack {
+    pub name: String,
+    pub artist_name: String,
+    pub duration_ms: u64,
+    pub track_number: u32,
+    pub disc_number: u32,
+    pub isrc: Option<String>,
+}
+
+impl AlbumTrack {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+}
+
+impl Album {
+    /// Finds the recognised song in the tracklist, by ISRC if we have one, by title otherwise.
+    pub fn find(&self, song: &SongRecognizedMessage) -> Option<&AlbumTrack> {
+        if let Some(isrc) = &song.isrc {
+            if let Some(track) = self.tracks.iter().find(|track| track.isrc.as_ref() == Some(isrc)) {
+                return Some(track);
+            }
+        }
+
+        self.tracks.iter().find(|track| track.name.eq_ignore_ascii_case(&song.song_name))
+    }
+
+    /// The track after `track` on the same disc (or side), if any.
+    pub fn next_after(&self, track: &AlbumTrack) -> Option<&AlbumTrack> {
+        self.tracks.iter().find(|other| other.disc_number == track.disc_number && other.track_number == track.track_number + 1)
+    }
+}
+
+// Apple Music catalogue response, as proxied by Shazam.
+
+#[derive(Deserialize)]
+pub struct AlbumResponse {
+    data: Vec<AlbumResource>,
+}
+
+#[derive(Deserialize)]
+struct AlbumResource {
+    attributes: AlbumAttributes,
+    relationships: AlbumRelationships,
+}
+
+#[derive(Deserialize)]
+struct AlbumAttributes {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct AlbumRelationships {
+    tracks: AlbumTracks,
+}
+
+#[derive(Deserialize)]
+struct AlbumTracks {
+    data: Vec<SongResource>,
+}
+
+#[derive(Deserialize)]
+struct SongResource {
+    attributes: SongAttributes,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SongAttributes {
+    name: String,
+    artist_name: String,
+    duration_in_millis: u64,
+    track_number: u32,
+    #[serde(default = "default_disc_number")]
+    disc_number: u32,
+    isrc: Option<String>,
+}
+
+fn default_disc_number() -> u32 {
+    1
+}
+
+impl AlbumResponse {
+    pub fn into_album(self) -> Result<Album, RecognitionError> {
+        let resource = self.data.into_iter().next()
+            .ok_or_else(|| RecognitionError::MalformedResponse("No album in response".into()))?;
+
+        Ok(Album {
+            name: resource.attributes.name,
+            tracks: resource.relationships.tracks.data.into_iter().map(|song| AlbumTrack {
+                name: song.attributes.name,
+                artist_name: song.attributes.artist_name,
+                duration_ms: song.attributes.duration_in_millis,
+                track_number: song.attributes.track_number,
+                disc_number: song.attributes.disc_number,
+                isrc: song.attributes.isrc,
+            }).collect(),
+        })
+    }
+}
+
+/// Tracklists we've fetched, keyed by Apple Music album ID. Albums don't change,
+/// so each one is only ever fetched once.
+pub struct AlbumCache {
+    path: PathBuf,
+    albums: HashMap<String, Album>,
+    /// Albums we couldn't get, not asked for again until restarting.
+    failed: HashSet<String>,
+}
+
+impl AlbumCache {
+    pub fn load(config: &Config) -> AlbumCache {
+        let path = config.data_dir.join(ALBUM_CACHE_FILE_NAME);
+
+        let albums = load_json(&path, "ALBUM");
+
+        AlbumCache { path, albums, failed: HashSet::new() }
+    }
+
+    /// `None` if fetching it already failed this session. Failing because
+    /// we're offline or throttled doesn't count, that's tried again.
+    pub async fn get(&mut self, recognizer: &Recognizer, album_id: &str) -> Result<Option<&Album>, RecognitionError> {
+        if self.failed.contains(album_id) {
+            return Ok(None);
+        }
+
+        if !self.albums.contains_key(album_id) {
+            match recognizer.get_album(album_id).await {
+                Ok(album) => {
+                    self.albums.insert(album_id.to_string(), album);
+                    self.save();
+                }
+                Err(e) => {
+                    if !e.is_offline() && !matches!(e, RecognitionError::Throttled { .. } | RecognitionError::OverBudget { .. }) {
+                        self.failed.insert(album_id.to_string());
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(self.albums.get(album_id))
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &self.albums, "ALBUM");
+    }
+}
+
+pub struct TrackPrediction {
+    pub current: AlbumTrack,
+    /// When the current track should end, going by its duration and where we are in it.
+    pub ends_at: SystemTime,
+    /// None at the end of the side.
+    pub next: Option<AlbumTrack>,
+}
+
+/// Works out when the recognised song ends and what comes after it on the album.
+pub async fn predict_next_track(recognizer: &Recognizer, albums: &mut AlbumCache, song: &SongRecognizedMessage) -> Result<Option<TrackPrediction>, RecognitionError> {
+    let (album_id, started_at) = match (&song.album_id, song.track_started_at()) {
+        (Some(album_id), Some(started_at)) => (album_id, started_at),
+        _ => return Ok(None),
+    };
+
+    let Some(album) = albums.get(recognizer, album_id).await? else {
+        return Ok(None);
+    };
+
+    let current = match album.find(song) {
+        Some(current) => current,
+        None => return Ok(None),
+    };
+
+    Ok(Some(TrackPrediction {
+        current: current.clone(),
+        ends_at: started_at + current.duration(),
+        next: album.next_after(current).cloned(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{message, song, temp_dir, Stub, SONG_A};
+
+    fn track(disc_number: u32, track_number: u32, name: &str, isrc: Option<&str>) -> serde_json::Value {
+        serde_json::json!({
+            "attributes": {
+                "name": name,
+                "artistName": "The Quiet Harbour",
+                "durationInMillis": 200_000 + track_number as u64 * 1_000,
+                "trackNumber": track_number,
+                "discNumber": disc_number,
+                "isrc": isrc,
+            }
+        })
+    }
+
+    /// A double album: the first side ends at track 3.
+    fn album_json() -> String {
+        serde_json::json!({
+            "data": [{
+                "attributes": { "name": "Low Tide Letters" },
+                "relationships": { "tracks": { "data": [
+                    track(1, 1, "Harbour Lights", Some("GBXYZ2100015")),
+                    track(1, 2, "Paper Lanterns", Some("GBXYZ2100017")),
+                    track(1, 3, "Paper Lanterns (Reprise)", Some("GBXYZ2100019")),
+                    track(2, 1, "Undertow", None),
+                    track(2, 2, "Paper Lanterns", Some("GBXYZ2100099")),
+                ]}}
+            }]
+        })
+        .to_string()
+    }
+
+    fn album() -> Album {
+        serde_json::from_str::<AlbumResponse>(&album_json()).unwrap().into_album().unwrap()
+    }
+
+    fn song_named(name: &str, isrc: Option<&str>) -> SongRecognizedMessage {
+        let mut song = message("612846392", &song(SONG_A, 0, 3, 1));
+        song.song_name = name.to_string();
+        song.isrc = isrc.map(str::to_string);
+        song
+    }
+
+    #[test]
+    fn finds_by_isrc() {
+        let album = album();
+
+        // The live version on the second disc has the same title
+        let found = album.find(&song_named("Paper Lanterns", Some("GBXYZ2100099"))).unwrap();
+        assert_eq!((found.disc_number, found.track_number), (2, 2));
+
+        // Even if the titles don't agree
+        let found = album.find(&song_named("Paper Lanterns - Reprise", Some("GBXYZ2100019"))).unwrap();
+        assert_eq!((found.disc_number, found.track_number), (1, 3));
+    }
+
+    #[test]
+    fn falls_back_to_the_title() {
+        let album = album();
+
+        let found = album.find(&song_named("paper lanterns", None)).unwrap();
+        assert_eq!((found.disc_number, found.track_number), (1, 2));
+
+        let found = album.find(&song_named("Undertow", Some("USABC0000000"))).unwrap();
+        assert_eq!((found.disc_number, found.track_number), (2, 1));
+
+        assert!(album.find(&song_named("Something Else", Some("USABC0000000"))).is_none());
+    }
+
+    #[test]
+    fn stops_at_the_end_of_a_side() {
+        let album = album();
+
+        let next = album.next_after(&album.tracks[1]).unwrap();
+        assert_eq!(next.name, "Paper Lanterns (Reprise)");
+
+        assert!(album.next_after(&album.tracks[2]).is_none());
+        assert!(album.next_after(&album.tracks[4]).is_none());
+    }
+
+    #[tokio::test]
+    async fn predicts_the_next_track() {
+        let album = album_json();
+        let stub = Stub::start(move |_| (200, album.clone())).await;
+        let mut config = Config {
+            data_dir: temp_dir("predict_next_track"),
+            ..Config::default()
+        };
+        config.http.web_base_url = stub.url.clone();
+        let recognizer = Recognizer::new(&config).unwrap();
+        let mut albums = AlbumCache::load(&config);
+
+        let mut song = song_named("Paper Lanterns", Some("GBXYZ2100017"));
+        song.album_id = Some("1589473001".to_string());
+        song.track_seek = Some(60.0);
+        let started_at = song.track_started_at().unwrap();
+
+        let prediction = predict_next_track(&recognizer, &mut albums, &song).await.unwrap().unwrap();
+        assert_eq!(prediction.current.track_number, 2);
+        assert_eq!(prediction.ends_at, started_at + Duration::from_secs(202));
+        assert_eq!(prediction.next.unwrap().name, "Paper Lanterns (Reprise)");
+
+        // The last track on the side has nothing after it
+        song.song_name = "Paper Lanterns (Reprise)".to_string();
+        song.isrc = None;
+        let prediction = predict_next_track(&recognizer, &mut albums, &song).await.unwrap().unwrap();
+        assert!(prediction.next.is_none());
+
+        // Without knowing where we are in the track there's nothing to predict
+        song.track_seek = None;
+        assert!(predict_next_track(&recognizer, &mut albums, &song).await.unwrap().is_none());
+
+        // The tracklist was only fetched once, and kept for next time
+        assert_eq!(stub.requests().len(), 1);
+        assert_eq!(stub.requests()[0].target, "/services/amapi/v1/catalog/US/albums/1589473001");
+        assert_eq!(AlbumCache::load(&config).albums["1589473001"].tracks.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn remembers_albums_it_cant_get() {
+        let stub = Stub::start(|_| (404, "{}".to_string())).await;
+        let mut config = Config {
+            data_dir: temp_dir("album_404"),
+            ..Config::default()
+        };
+        config.http.web_base_url = stub.url.clone();
+        let recognizer = Recognizer::new(&config).unwrap();
+        let mut albums = AlbumCache::load(&config);
+
+        assert!(matches!(albums.get(&recognizer, "1589473001").await, Err(RecognitionError::HttpStatus { .. })));
+        assert!(albums.get(&recognizer, "1589473001").await.unwrap().is_none());
+        assert_eq!(stub.requests().len(), 1);
+
+        // Only for this session
+        assert!(AlbumCache::load(&config).get(&recognizer, "1589473001").await.is_err());
+        assert_eq!(stub.requests().len(), 2);
+    }
+}
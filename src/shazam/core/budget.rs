@@ -0,0 +1,256 @@
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::utils::{load_json, save_json};
+
+const BUDGET_FILE_NAME: &str = "request_budget.json";
+/// Held while updating the budget. Separate from the budget itself since
+/// that gets replaced on every save.
+const LOCK_FILE_NAME: &str = "request_budget.lock";
+
+/// Below this fraction of a bucket left, queries get spaced out to its refill rate.
+const TIGHT_FRACTION: f64 = 0.5;
+
+#[derive(Serialize, Deserialize, Default)]
+struct BudgetState {
+    hourly_tokens: Option<f64>,
+    daily_tokens: Option<f64>,
+    /// Unix time (seconds) the tokens were last counted at.
+    updated_at: f64,
+}
+
+/// Fills up to `capacity` tokens over `period`.
+struct Bucket {
+    capacity: f64,
+    period: Duration,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(capacity: u32, period: Duration) -> Bucket {
+        Bucket {
+            capacity: capacity as f64,
+            period,
+            tokens: capacity as f64,
+        }
+    }
+
+    /// Puts back a saved token count. Full if there's none.
+    fn restore(&mut self, tokens: Option<f64>) {
+        self.tokens = tokens.unwrap_or(self.capacity).clamp(0.0, self.capacity);
+    }
+
+    /// Seconds per token.
+    fn refill_interval(&self) -> f64 {
+        self.period.as_secs_f64() / self.capacity
+    }
+
+    fn refill(&mut self, elapsed: f64) {
+        self.tokens = (self.tokens + elapsed / self.refill_interval()).min(self.capacity);
+    }
+
+    fn time_until_available(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) * self.refill_interval()).max(0.0))
+    }
+
+    fn is_tight(&self) -> bool {
+        self.tokens < self.capacity * TIGHT_FRACTION
+    }
+}
+
+/// Token buckets limiting how many recognition requests we make per hour and
+/// per day, saved in `data_dir` so restarting doesn't reset them. Other
+/// processes using the same `data_dir` draw from the same buckets, so the
+/// saved state is re-read before every change.
+pub struct RequestBudget {
+    path: PathBuf,
+    lock_path: PathBuf,
+    hourly: Option<Bucket>,
+    daily: Option<Bucket>,
+    updated_at: SystemTime,
+}
+
+impl RequestBudget {
+    pub fn load(config: &Config) -> RequestBudget {
+        let mut budget = RequestBudget {
+            path: config.data_dir.join(BUDGET_FILE_NAME),
+            lock_path: config.data_dir.join(LOCK_FILE_NAME),
+            hourly: config.budget.requests_per_hour
+                .map(|capacity| Bucket::new(capacity.max(1), Duration::from_secs(3600))),
+            daily: config.budget.requests_per_day
+                .map(|capacity| Bucket::new(capacity.max(1), Duration::from_secs(86400))),
+            updated_at: SystemTime::UNIX_EPOCH,
+        };
+        budget.reload();
+
+        budget
+    }
+
+    /// Uses up a request if there's one left, otherwise says how long until there is.
+    pub fn take(&mut self) -> Result<(), Duration> {
+        if self.hourly.is_none() && self.daily.is_none() {
+            return Ok(());
+        }
+
+        // Nobody else can take a request between our reading the budget and saving it
+        let _lock = self.lock();
+        self.reload();
+
+        let wait = self.buckets()
+            .map(Bucket::time_until_available)
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for bucket in self.hourly.iter_mut().chain(self.daily.iter_mut()) {
+            bucket.tokens -= 1.0;
+        }
+        self.save();
+
+        Ok(())
+    }
+
+    /// How long to hold off between queries so the budget lasts, when it's running low.
+    pub fn pacing(&mut self) -> Option<Duration> {
+        self.reload();
+
+        self.buckets()
+            .filter(|bucket| bucket.is_tight())
+            .map(|bucket| Duration::from_secs_f64(bucket.refill_interval()))
+            .max()
+    }
+
+    fn buckets(&self) -> impl Iterator<Item = &Bucket> {
+        self.hourly.iter().chain(self.daily.iter())
+    }
+
+    /// Picks up what's been taken since we last looked, by us or anyone else,
+    /// and what's been refilled since.
+    fn reload(&mut self) {
+        let state: BudgetState = load_json(&self.path, "BUDGET");
+
+        if let Some(bucket) = &mut self.hourly {
+            bucket.restore(state.hourly_tokens);
+        }
+        if let Some(bucket) = &mut self.daily {
+            bucket.restore(state.daily_tokens);
+        }
+        self.updated_at = SystemTime::UNIX_EPOCH + Duration::from_secs_f64(state.updated_at);
+
+        self.refill();
+    }
+
+    /// Locks the budget until the returned file is dropped. If it can't be
+    /// locked we carry on without, the worst that can happen is a few extra requests.
+    fn lock(&self) -> Option<File> {
+        if let Some(parent) = self.lock_path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+
+        let res = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.lock_path)
+            .and_then(|file| file.lock().map(|_| file));
+
+        match res {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!("[BUDGET] Failed to lock {}: {}", self.lock_path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = SystemTime::now();
+        let elapsed = now.duration_since(self.updated_at).unwrap_or_default().as_secs_f64();
+
+        for bucket in self.hourly.iter_mut().chain(self.daily.iter_mut()) {
+            bucket.refill(elapsed);
+        }
+        self.updated_at = now;
+    }
+
+    fn save(&self) {
+        let state = BudgetState {
+            hourly_tokens: self.hourly.as_ref().map(|bucket| bucket.tokens),
+            daily_tokens: self.daily.as_ref().map(|bucket| bucket.tokens),
+            updated_at: self.updated_at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+        };
+        save_json(&self.path, &state, "BUDGET");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BudgetConfig;
+    use crate::testing::temp_dir;
+
+    fn config(name: &str, requests_per_hour: u32) -> Config {
+        Config {
+            data_dir: temp_dir(name),
+            budget: BudgetConfig {
+                requests_per_hour: Some(requests_per_hour),
+                requests_per_day: None,
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn is_shared_between_instances() {
+        let config = config("budget_shared", 3);
+        let mut first = RequestBudget::load(&config);
+        let mut second = RequestBudget::load(&config);
+
+        assert!(first.take().is_ok());
+        assert!(second.take().is_ok());
+        assert!(first.take().is_ok());
+
+        // The second one has never seen the budget run out, but it has
+        let wait = second.take().unwrap_err();
+        assert!(wait > Duration::from_secs(1150) && wait <= Duration::from_secs(1200), "{:?}", wait);
+        assert!(first.take().is_err());
+        assert!(RequestBudget::load(&config).take().is_err());
+    }
+
+    #[test]
+    fn never_hands_out_more_than_it_has() {
+        let config = config("budget_concurrent", 20);
+
+        let taken: usize = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut budget = RequestBudget::load(&config);
+                        (0..5).filter(|_| budget.take().is_ok()).count()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).sum()
+        });
+
+        assert_eq!(taken, 20);
+    }
+
+    #[test]
+    fn paces_when_running_low() {
+        let config = config("budget_pacing", 4);
+        let mut budget = RequestBudget::load(&config);
+
+        assert_eq!(budget.pacing(), None);
+        RequestBudget::load(&config).take().unwrap();
+        RequestBudget::load(&config).take().unwrap();
+        RequestBudget::load(&config).take().unwrap();
+        assert_eq!(budget.pacing(), Some(Duration::from_secs(900)));
+    }
+}
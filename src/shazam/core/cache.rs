@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+#[cfg(feature = "shazam-client")]
+use crate::shazam::core::response::Track;
+use crate::shazam::core::thread_messages::{MusicBrainzInfo, SongRecognizedMessage, TrackLinks};
+use crate::shazam::fingerprinting::signature_format::{landmark_overlap, DecodedSignature};
+use crate::utils::{load_json, save_json};
+
+const CACHE_FILE_NAME: &str = "track_cache.json";
+
+/// Everything we know about a track, keyed by its Shazam `track_key`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CachedTrack {
+    pub artist_name: String,
+    pub album_name: Option<String>,
+    pub song_name: String,
+    pub cover_image: Option<String>,
+    pub release_year: Option<String>,
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub isrc: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub share_url: Option<String>,
+    #[serde(default)]
+    pub links: TrackLinks,
+    #[serde(default)]
+    pub lyrics: Option<Vec<String>>,
+    #[serde(default)]
+    pub album_id: Option<String>,
+    #[serde(default)]
+    pub musicbrainz: Option<MusicBrainzInfo>,
+}
+
+#[cfg(feature = "shazam-client")]
+impl From<&Track> for CachedTrack {
+    fn from(track: &Track) -> Self {
+        CachedTrack {
+            artist_name: track.subtitle.clone(),
+            album_name: track.metadata("Album").map(str::to_string),
+            song_name: track.title.clone(),
+            cover_image: track.images.as_ref().and_then(|images| images.coverart.clone()),
+            release_year: track.metadata("Released").map(str::to_string),
+            genre: track.genres.as_ref().and_then(|genres| genres.primary.clone()),
+            isrc: track.isrc.clone(),
+            label: track.metadata("Label").map(str::to_string),
+            share_url: track.share_url().map(str::to_string),
+            links: TrackLinks {
+                apple_music: track.apple_music_uri().map(str::to_string),
+                spotify: track.provider_uri("SPOTIFY").map(str::to_string),
+                youtube_music: track.provider_uri("YOUTUBEMUSIC").map(str::to_string),
+            },
+            lyrics: track.lyrics().map(<[String]>::to_vec),
+            album_id: track.albumadamid.clone(),
+            musicbrainz: None,
+        }
+    }
+}
+
+struct LastMatch {
+    track_key: String,
+    track_seek: f32,
+    timestamp: SystemTime,
+    /// From the matched window and every window extrapolated since.
+    landmarks: HashSet<u64>,
+    extrapolated_windows: u32,
+}
+
+pub struct TrackCache {
+    path: PathBuf,
+    tracks: HashMap<String, CachedTrack>,
+    last_match: Option<LastMatch>,
+
+    similarity_threshold: f32,
+    max_extrapolated_windows: u32,
+}
+
+impl TrackCache {
+    pub fn load(config: &Config) -> TrackCache {
+        let path = config.data_dir.join(CACHE_FILE_NAME);
+
+        let tracks = load_json(&path, "CACHE");
+
+        TrackCache {
+            path,
+            tracks,
+            last_match: None,
+            similarity_threshold: config.cache.similarity_threshold,
+            max_extrapolated_windows: config.cache.max_extrapolated_windows,
+        }
+    }
+
+    /// Fills in anything the backend left out from what we already know about
+    /// the track, stores the result and remembers it as the last match.
+    pub fn merge(&mut self, song: &mut SongRecognizedMessage) {
+        if let Some(cached) = self.tracks.get(&song.track_key) {
+            song.album_name = song.album_name.take().or_else(|| cached.album_name.clone());
+            song.cover_image = song.cover_image.take().or_else(|| cached.cover_image.clone());
+            song.release_year = song.release_year.take().or_else(|| cached.release_year.clone());
+            song.genre = song.genre.take().or_else(|| cached.genre.clone());
+            song.isrc = song.isrc.take().or_else(|| cached.isrc.clone());
+            song.label = song.label.take().or_else(|| cached.label.clone());
+            song.share_url = song.share_url.take().or_else(|| cached.share_url.clone());
+            song.links.apple_music = song.links.apple_music.take().or_else(|| cached.links.apple_music.clone());
+            song.links.spotify = song.links.spotify.take().or_else(|| cached.links.spotify.clone());
+            song.links.youtube_music = song.links.youtube_music.take().or_else(|| cached.links.youtube_music.clone());
+            song.lyrics = song.lyrics.take().or_else(|| cached.lyrics.clone());
+            song.album_id = song.album_id.take().or_else(|| cached.album_id.clone());
+            song.musicbrainz = song.musicbrainz.take().or_else(|| cached.musicbrainz.clone());
+        }
+
+        self.tracks.insert(song.track_key.clone(), CachedTrack {
+            artist_name: song.artist_name.clone(),
+            album_name: song.album_name.clone(),
+            song_name: song.song_name.clone(),
+            cover_image: song.cover_image.clone(),
+            release_year: song.release_year.clone(),
+            genre: song.genre.clone(),
+            isrc: song.isrc.clone(),
+            label: song.label.clone(),
+            share_url: song.share_url.clone(),
+            links: song.links.clone(),
+            lyrics: song.lyrics.clone(),
+            album_id: song.album_id.clone(),
+            musicbrainz: song.musicbrainz.clone(),
+        });
+        self.save();
+
+        self.last_match = song.track_seek.map(|track_seek| LastMatch {
+            track_key: song.track_key.clone(),
+            track_seek,
+            timestamp: song.timestamp,
+            landmarks: song.signature.landmarks(),
+            extrapolated_windows: 0,
+        });
+    }
+
+    /// If `signature` sounds like a continuation of the last matched track,
+    /// builds a message for it from the cache without going to the network.
+    /// Gives the signature back otherwise.
+    pub fn extrapolate(&mut self, signature: DecodedSignature, timestamp: SystemTime) -> Result<SongRecognizedMessage, DecodedSignature> {
+        let last_match = match &mut self.last_match {
+            Some(last_match) => last_match,
+            None => return Err(signature),
+        };
+
+        if last_match.extrapolated_windows >= self.max_extrapolated_windows {
+            return Err(signature);
+        }
+
+        let landmarks = signature.landmarks();
+        if landmark_overlap(&last_match.landmarks, &landmarks) < self.similarity_threshold {
+            return Err(signature);
+        }
+
+        let track = match self.tracks.get(&last_match.track_key) {
+            Some(track) => track,
+            None => return Err(signature),
+        };
+
+        let elapsed = timestamp.duration_since(last_match.timestamp).unwrap_or_default();
+        last_match.extrapolated_windows += 1;
+        last_match.landmarks.extend(landmarks);
+
+        Ok(SongRecognizedMessage {
+            artist_name: track.artist_name.clone(),
+            album_name: track.album_name.clone(),
+            song_name: track.song_name.clone(),
+            cover_image: track.cover_image.clone(),
+            local_cover: None,
+            track_seek: Some(last_match.track_seek + elapsed.as_secs_f32()),
+            matches: vec![],
+            signature: Box::new(signature),
+            track_key: last_match.track_key.clone(),
+            release_year: track.release_year.clone(),
+            genre: track.genre.clone(),
+            isrc: track.isrc.clone(),
+            label: track.label.clone(),
+            share_url: track.share_url.clone(),
+            links: track.links.clone(),
+            lyrics: track.lyrics.clone(),
+            album_id: track.album_id.clone(),
+            musicbrainz: track.musicbrainz.clone(),
+            duration: track.musicbrainz.as_ref().and_then(|mb| mb.length_ms).map(Duration::from_millis),
+            shazam_json: String::new(),
+            timestamp,
+            extrapolated: true,
+        })
+    }
+
+    /// Replaces whatever we had for `track_key`, e.g. after looking it up again.
+    pub fn insert(&mut self, track_key: String, track: CachedTrack) {
+        self.tracks.insert(track_key, track);
+        self.save();
+    }
+
+    /// Remembers where the track is in MusicBrainz so we only look it up once.
+    pub fn set_musicbrainz(&mut self, track_key: &str, info: MusicBrainzInfo) {
+        if let Some(track) = self.tracks.get_mut(track_key) {
+            track.musicbrainz = Some(info);
+            self.save();
+        }
+    }
+
+    /// Stops extrapolating from the last match, e.g. after silence.
+    pub fn forget_last_match(&mut self) {
+        self.last_match = None;
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &self.tracks, "CACHE");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::shazam::fingerprinting::algorithm::SignatureGenerator;
+    use crate::testing::{message, song, temp_dir, SONG_A, SONG_B};
+
+    #[test]
+    fn only_extrapolates_the_same_recording() {
+        let config = Config {
+            data_dir: temp_dir("cache"),
+            ..Config::default()
+        };
+        let mut cache = TrackCache::load(&config);
+
+        let mut matched = message("a", &song(SONG_A, 0, 12, 1));
+        matched.track_seek = Some(30.0);
+        cache.merge(&mut matched);
+        let later = matched.timestamp + Duration::from_secs(12);
+
+        let other = SignatureGenerator::make_signature_from_buffer(&song(SONG_B, 12, 12, 2));
+        assert!(cache.extrapolate(other, later).is_err());
+
+        let same = SignatureGenerator::make_signature_from_buffer(&song(SONG_A, 12, 12, 2));
+        match cache.extrapolate(same, later) {
+            Ok(extrapolated) => {
+                assert_eq!(extrapolated.track_key, "a");
+                assert!(extrapolated.extrapolated);
+                assert_eq!(extrapolated.track_seek, Some(42.0));
+            }
+            Err(_) => panic!("continuation of the same recording wasn't extrapolated"),
+        }
+    }
+}
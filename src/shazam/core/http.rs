@@ -1,80 +1,69 @@
 use std::time::SystemTime;
 use regex::Regex;
-use serde_json::{Value, to_string_pretty};
+use serde_json::to_string_pretty;
 
+#[cfg(feature = "local-db")]
+use crate::shazam::core::cache::{CachedTrack, TrackCache};
+use crate::shazam::core::response::DiscoveryResponse;
 use crate::shazam::core::thread_messages::*;
+use crate::shazam::error::RecognitionError;
 
 use crate::shazam::fingerprinting::signature_format::DecodedSignature;
-use crate::shazam::fingerprinting::communication::recognize_song_from_signature;
+use crate::shazam::fingerprinting::communication::Recognizer;
+
+pub async fn try_recognize_song(recognizer: &Recognizer, signature: DecodedSignature, timestamp: SystemTime) -> Result<SongRecognizedMessage, RecognitionError> {
+    let json_object = recognizer.recognize_song_from_signature(&signature, timestamp).await?;
+    let response = DiscoveryResponse::from_json(&json_object)?;
+
+    let (track, best_match) = match (&response.track, response.matches.first()) {
+        (Some(track), Some(best_match)) => (track, best_match),
+        _ => return Err(RecognitionError::NoMatch { retry_after: response.retry_after() }),
+    };
 
-pub async fn try_recognize_song(signature: DecodedSignature) -> Result<SongRecognizedMessage, String> {
-    let timestamp = SystemTime::now();
-    
-    let json_object = recognize_song_from_signature(&signature).await?;
-    
-    let mut album_name: Option<String> = None;
-    let mut release_year: Option<String> = None;
-    
-    // Sometimes the idea of trying to write functional poetry hurts
-    
-    if let Value::Array(sections) = &json_object["track"]["sections"] {
-        for section in sections {
-            if let Value::String(string) = &section["type"] {
-                if string == "SONG" {
-                    if let Value::Array(metadata) = &section["metadata"] {
-                        for metadatum in metadata {
-                            if let Value::String(title) = &metadatum["title"] {
-                                if title == "Album" {
-                                    if let Value::String(text) = &metadatum["text"] {
-                                        album_name = Some(text.to_string());
-                                    }
-                                }
-                                else if title == "Released" {
-                                    if let Value::String(text) = &metadatum["text"] {
-                                        release_year = Some(text.to_string());
-                                    }
-                                }
-                            }
-                        }
-                        break;
-                    }
-                }
-            }
-        }
-    }
-    
     Ok(SongRecognizedMessage {
-        artist_name: match &json_object["track"]["subtitle"] {
-            Value::String(string) => string.to_string(),
-            _ => { return Err("No match for this song".to_string()) }
-        },
-        album_name: album_name,
-        song_name: match &json_object["track"]["title"] {
-            Value::String(string) => string.to_string(),
-            _ => { return Err("No match for this song".to_string()) }
-        },
-        cover_image: match &json_object["track"]["images"]["coverart"] {
-            Value::String(string) => Some(string.to_string()),
-            _ => None
-        },
-        track_seek: match &json_object["matches"][0]["offset"] {
-            Value::Number(number) => Some(number.as_f64().unwrap() as f32),
-            _ => None
-        },
+        artist_name: track.subtitle.clone(),
+        album_name: track.metadata("Album").map(str::to_string),
+        song_name: track.title.clone(),
+        cover_image: track.images.as_ref().and_then(|images| images.coverart.clone()),
+        local_cover: None,
+        track_seek: Some(best_match.offset as f32),
+        matches: response.matches.iter().map(|candidate| MatchCandidate {
+            id: candidate.id.clone(),
+            offset: candidate.offset as f32,
+            time_skew: candidate.timeskew.map(|skew| skew as f32),
+            frequency_skew: candidate.frequencyskew.map(|skew| skew as f32),
+            confidence: candidate.confidence(),
+        }).collect(),
         signature: Box::new(signature),
-        track_key: match &json_object["track"]["key"] {
-            Value::String(string) => string.to_string(),
-            _ => { return Err("No match for this song".to_string()) }
-        },
-        release_year: release_year,
-        genre: match &json_object["track"]["genres"]["primary"] {
-            Value::String(string) => Some(string.to_string()),
-            _ => None
+        track_key: track.key.clone(),
+        release_year: track.metadata("Released").map(str::to_string),
+        genre: track.genres.as_ref().and_then(|genres| genres.primary.clone()),
+        isrc: track.isrc.clone(),
+        label: track.metadata("Label").map(str::to_string),
+        share_url: track.share_url().map(str::to_string),
+        links: TrackLinks {
+            apple_music: track.apple_music_uri().map(str::to_string),
+            spotify: track.provider_uri("SPOTIFY").map(str::to_string),
+            youtube_music: track.provider_uri("YOUTUBEMUSIC").map(str::to_string),
         },
+        lyrics: track.lyrics().map(<[String]>::to_vec),
+        album_id: track.albumadamid.clone(),
+        musicbrainz: None,
+        duration: None,
         shazam_json: Regex::new("\n *").unwrap().replace_all(&
             Regex::new("([,:])\n *").unwrap().replace_all(&
-                to_string_pretty(&json_object).unwrap(), "$1 ").into_owned(),
+                to_string_pretty(&json_object).unwrap(), "$1 "),
             "").into_owned(),
         timestamp,
+        extrapolated: false,
     })
 }
+
+/// Looks a track up by key and stores the full metadata in the cache.
+#[cfg(feature = "local-db")]
+pub async fn refresh_cached_track(recognizer: &Recognizer, cache: &mut TrackCache, track_key: &str) -> Result<CachedTrack, RecognitionError> {
+    let track = CachedTrack::from(&recognizer.get_track(track_key).await?);
+    cache.insert(track_key.to_string(), track.clone());
+
+    Ok(track)
+}
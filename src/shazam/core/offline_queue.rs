@@ -0,0 +1,379 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex};
+
+use crate::backends::{try_backends, Backend};
+use crate::config::Config;
+use crate::events::Event;
+use crate::shazam::error::RecognitionError;
+use crate::shazam::fingerprinting::signature_format::DecodedSignature;
+use crate::utils::{load_json, save_json, write_atomic};
+
+const QUEUE_FILE_NAME: &str = "offline_queue.json";
+/// Where the audio of queued windows goes, for the backends that need more than the signature.
+const AUDIO_DIR_NAME: &str = "offline_audio";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct QueuedSignature {
+    pub signature_uri: String,
+    /// Unix time (milliseconds) the audio was captured at.
+    pub captured_at_ms: u64,
+    /// Raw s16le audio in the audio directory, if it was kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_file: Option<String>,
+}
+
+/// Signatures that couldn't be sent, kept on disk until the network comes back.
+pub struct OfflineQueue {
+    path: PathBuf,
+    audio_dir: PathBuf,
+    entries: VecDeque<QueuedSignature>,
+    max_entries: usize,
+}
+
+impl OfflineQueue {
+    pub fn load(config: &Config) -> OfflineQueue {
+        let path = config.data_dir.join(QUEUE_FILE_NAME);
+
+        let entries: VecDeque<QueuedSignature> = load_json(&path, "QUEUE");
+
+        OfflineQueue {
+            path,
+            audio_dir: config.data_dir.join(AUDIO_DIR_NAME),
+            entries,
+            max_entries: config.offline_queue.max_entries,
+        }
+    }
+
+    /// `audio` is only needed for backends other than Shazam, leave it out otherwise.
+    /// Fails if the audio couldn't be saved, the signature is queued anyway.
+    pub fn push(&mut self, signature_uri: String, captured_at: SystemTime, audio: Option<&[i16]>) -> Result<(), String> {
+        let captured_at_ms = captured_at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+
+        let mut res = Ok(());
+        let audio_file = audio.and_then(|audio| {
+            let file_name = format!("{}.s16", captured_at_ms);
+            let mut bytes = vec![0; audio.len() * 2];
+            LittleEndian::write_i16_into(audio, &mut bytes);
+
+            match write_atomic(&self.audio_dir.join(&file_name), &bytes) {
+                Ok(()) => Some(file_name),
+                Err(e) => {
+                    res = Err(format!("Failed to save the audio of a queued window, only its signature will be retried: {}", e));
+                    None
+                }
+            }
+        });
+
+        self.entries.push_back(QueuedSignature {
+            signature_uri,
+            captured_at_ms,
+            audio_file,
+        });
+
+        while self.entries.len() > self.max_entries {
+            if let Some(evicted) = self.entries.pop_front() {
+                self.delete_audio(&evicted);
+            }
+        }
+
+        self.save();
+        res
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn front(&self) -> Option<QueuedSignature> {
+        self.entries.front().cloned()
+    }
+
+    /// Removes `entry` once it's been dealt with. It may be gone already if it
+    /// was pushed out by newer ones while we were busy with it. Returns how
+    /// many are left.
+    pub fn remove(&mut self, entry: &QueuedSignature) -> usize {
+        if let Some(index) = self.entries.iter().position(|queued| queued == entry) {
+            self.entries.remove(index);
+            self.delete_audio(entry);
+            self.save();
+        }
+        self.entries.len()
+    }
+
+    /// The audio saved with `entry`, empty if there's none.
+    pub fn audio(&self, entry: &QueuedSignature) -> Vec<i16> {
+        let Some(file_name) = &entry.audio_file else {
+            return vec![];
+        };
+
+        match fs::read(self.audio_dir.join(file_name)) {
+            Ok(bytes) => {
+                let mut audio = vec![0; bytes.len() / 2];
+                LittleEndian::read_i16_into(&bytes[..audio.len() * 2], &mut audio);
+                audio
+            }
+            Err(_) => vec![],
+        }
+    }
+
+    fn delete_audio(&self, entry: &QueuedSignature) {
+        if let Some(file_name) = &entry.audio_file {
+            let _ = fs::remove_file(self.audio_dir.join(file_name));
+        }
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &self.entries, "QUEUE");
+    }
+}
+
+/// Keeps retrying queued signatures through `backends` with exponential
+/// backoff, publishing whatever gets recognised as [`Event::LateMatch`].
+/// Entries are only dropped once the server has given a definite answer, not
+/// while it's down.
+pub fn spawn_retry_worker(backends: Arc<Vec<Backend>>, queue: Arc<Mutex<OfflineQueue>>, events: broadcast::Sender<Event>, initial_delay: Duration, max_delay: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        // Only fails when nobody is subscribed
+        let publish = |event: Event| {
+            let _ = events.send(event);
+        };
+
+        let left_over = queue.lock().await.len();
+        if left_over > 0 {
+            publish(Event::QueueLength(left_over));
+        }
+
+        let mut delay = initial_delay;
+
+        loop {
+            tokio::time::sleep(delay).await;
+
+            let entry = match queue.lock().await.front() {
+                Some(entry) => entry,
+                None => {
+                    delay = initial_delay;
+                    continue;
+                }
+            };
+
+            let signature = match DecodedSignature::decode_from_uri(&entry.signature_uri) {
+                Ok(signature) => signature,
+                Err(e) => {
+                    publish(Event::DroppedFromQueue(format!("Undecodable signature: {}", e)));
+                    publish(Event::QueueLength(queue.lock().await.remove(&entry)));
+                    continue;
+                }
+            };
+
+            let captured_at = SystemTime::UNIX_EPOCH + Duration::from_millis(entry.captured_at_ms);
+            let audio = queue.lock().await.audio(&entry);
+
+            let on_failure = |backend: &Backend, e: &RecognitionError| publish(Event::BackendFailed { backend: backend.name(), error: e.to_string() });
+            match try_backends(&backends, &audio, &signature, captured_at, on_failure).await {
+                Ok(song) => {
+                    publish(Event::LateMatch(Arc::new(song)));
+                    publish(Event::QueueLength(queue.lock().await.remove(&entry)));
+                    delay = initial_delay;
+                }
+                Err(RecognitionError::OverBudget { retry_after }) => {
+                    publish(Event::BudgetExhausted(retry_after));
+                    delay = retry_after.clamp(initial_delay, max_delay);
+                }
+                Err(RecognitionError::Throttled { retry_after }) => {
+                    delay = retry_after.unwrap_or(delay * 2).min(max_delay);
+                    publish(Event::Error(format!("Throttled while sending queued signatures, retrying in {}s", delay.as_secs())));
+                }
+                Err(e) if e.is_temporary() => {
+                    delay = (delay * 2).min(max_delay);
+                    publish(Event::Error(format!("Couldn't send queued signatures ({}), retrying in {}s", e, delay.as_secs())));
+                }
+                // No match, or a definite no from the server
+                Err(e) => {
+                    publish(Event::DroppedFromQueue(e.to_string()));
+                    publish(Event::QueueLength(queue.lock().await.remove(&entry)));
+                    delay = initial_delay;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    use super::*;
+    use crate::config::{AudDConfig, BackendKind};
+    use crate::shazam::core::thread_messages::SongRecognizedMessage;
+    use crate::shazam::fingerprinting::algorithm::SignatureGenerator;
+    use crate::shazam::fingerprinting::communication::Recognizer;
+    use crate::testing::{song, temp_dir, Stub, SONG_A};
+
+    fn queue(name: &str) -> OfflineQueue {
+        let mut config = Config {
+            data_dir: temp_dir(name),
+            ..Config::default()
+        };
+        config.offline_queue.max_entries = 2;
+        OfflineQueue::load(&config)
+    }
+
+    fn at(ms: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn removing_an_evicted_entry_leaves_the_rest() {
+        let mut queue = queue("queue_evict");
+        queue.push("a".to_string(), at(1), None).unwrap();
+        queue.push("b".to_string(), at(2), None).unwrap();
+
+        // The worker picks up "a", then "c" pushes it out before it's done
+        let sent = queue.front().unwrap();
+        queue.push("c".to_string(), at(3), None).unwrap();
+        queue.remove(&sent);
+
+        assert_eq!(queue.front().unwrap().signature_uri, "b");
+        queue.remove(&queue.front().unwrap());
+        assert_eq!(queue.front().unwrap().signature_uri, "c");
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let mut config = Config {
+            data_dir: temp_dir("queue_restart"),
+            ..Config::default()
+        };
+        config.offline_queue.max_entries = 10;
+
+        let mut queue = OfflineQueue::load(&config);
+        queue.push("a".to_string(), at(1), None).unwrap();
+        queue.push("b".to_string(), at(2), None).unwrap();
+        queue.remove(&queue.front().unwrap());
+
+        let reloaded = OfflineQueue::load(&config);
+        assert_eq!(reloaded.front(), Some(QueuedSignature { signature_uri: "b".to_string(), captured_at_ms: 2, audio_file: None }));
+        let leftovers = std::fs::read_dir(&config.data_dir).unwrap().filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".tmp"));
+        assert_eq!(leftovers.count(), 0);
+    }
+
+    fn config(stub: &Stub, name: &str) -> Config {
+        let mut config = Config {
+            data_dir: temp_dir(name),
+            ..Config::default()
+        };
+        config.http.base_url = stub.url.clone();
+        config.http.max_attempts = 1;
+        config
+    }
+
+    /// Runs the worker with one window queued, until `done` says so.
+    async fn retry(config: &Config, keep_audio: bool, done: impl Fn(&OfflineQueue) -> bool) -> (Arc<Mutex<OfflineQueue>>, Vec<Event>) {
+        let audio = song(SONG_A, 0, 3, 1);
+        let signature_uri = SignatureGenerator::make_signature_from_buffer(&audio).encode_to_uri().unwrap();
+        let queue = Arc::new(Mutex::new(OfflineQueue::load(config)));
+        queue.lock().await.push(signature_uri, at(1_000), keep_audio.then_some(&audio[..])).unwrap();
+
+        let backends = Backend::from_config(config, &Recognizer::new(config).unwrap()).unwrap();
+        let events = crate::events::channel();
+        let mut receiver = events.subscribe();
+        let worker = spawn_retry_worker(Arc::new(backends), queue.clone(), events, Duration::from_millis(10), Duration::from_millis(40));
+        for _ in 0..200 {
+            if done(&*queue.lock().await) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        worker.abort();
+
+        let mut published = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            published.push(event);
+        }
+        (queue, published)
+    }
+
+    fn late_matches(events: &[Event]) -> Vec<&SongRecognizedMessage> {
+        events.iter().filter_map(|event| match event {
+            Event::LateMatch(song) => Some(&**song),
+            _ => None,
+        }).collect()
+    }
+
+    fn queue_lengths(events: &[Event]) -> Vec<usize> {
+        events.iter().filter_map(|event| match event {
+            Event::QueueLength(pending) => Some(*pending),
+            _ => None,
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_entries_while_the_server_is_down() {
+        let fixture = std::fs::read_to_string(format!("{}/tests/fixtures/discovery_match.json", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let status = Arc::new(AtomicU16::new(503));
+        let stub_status = status.clone();
+        let stub = Stub::start(move |_| match stub_status.load(Ordering::SeqCst) {
+            200 => (200, fixture.clone()),
+            status => (status, "<html>Service Unavailable</html>".to_string()),
+        })
+        .await;
+
+        let (queue, events) = retry(&config(&stub, "queue_outage"), false, |_| stub.requests().len() >= 3).await;
+        assert!(queue.lock().await.front().is_some());
+        assert!(late_matches(&events).is_empty());
+        assert_eq!(queue_lengths(&events), [1]);
+        assert!(!events.iter().any(|event| matches!(event, Event::DroppedFromQueue(_))));
+
+        // Back up again
+        status.store(200, Ordering::SeqCst);
+        let (queue, events) = retry(&config(&stub, "queue_outage_over"), false, |queue| queue.front().is_none()).await;
+        assert!(queue.lock().await.front().is_none());
+        let late = late_matches(&events);
+        assert_eq!(late.len(), 1);
+        assert_eq!(late[0].song_name, "Paper Lanterns");
+        assert_eq!(late[0].timestamp, at(1_000));
+        assert_eq!(queue_lengths(&events), [1, 0]);
+    }
+
+    #[tokio::test]
+    async fn drops_entries_the_server_refuses() {
+        let stub = Stub::start(|_| (400, "{}".to_string())).await;
+
+        let (queue, events) = retry(&config(&stub, "queue_refused"), false, |queue| queue.front().is_none()).await;
+        assert!(queue.lock().await.front().is_none());
+        assert_eq!(stub.requests().len(), 1);
+        assert!(late_matches(&events).is_empty());
+        assert!(events.iter().any(|event| matches!(event, Event::DroppedFromQueue(reason) if reason.contains("400"))));
+    }
+
+    #[tokio::test]
+    async fn retries_through_the_configured_backends() {
+        let response = r#"{"status": "success", "result": {"artist": "The Quiet Harbour", "title": "Paper Lanterns", "song_link": "https://lis.tn/PaperLanterns"}}"#;
+        let stub = Stub::start(move |_| (200, response.to_string())).await;
+        let mut config = config(&stub, "queue_audd");
+        config.backends = vec![BackendKind::Audd];
+        config.audd = Some(AudDConfig { api_token: "test-token".to_string(), base_url: format!("{}/", stub.url) });
+
+        let (queue, events) = retry(&config, true, |queue| queue.front().is_none()).await;
+        assert!(queue.lock().await.front().is_none());
+        assert_eq!(late_matches(&events)[0].track_key, "audd:https://lis.tn/PaperLanterns");
+
+        // AudD got the audio back from disk, which was cleaned up after
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 1);
+        let file = requests[0].multipart().into_iter().find(|part| part.name == "file").unwrap();
+        assert_eq!(file.data.len(), 44 + 3 * 16_000 * 2);
+        assert_eq!(std::fs::read_dir(config.data_dir.join(AUDIO_DIR_NAME)).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,314 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::shazam::error::RecognitionError;
+
+/// Response to a `discovery/v5/.../tag` request.
+///
+/// Only the fields we rely on are required, so a change to those fails loudly
+/// with the path of the offending field instead of silently producing nothing.
+#[derive(Deserialize, Debug)]
+pub struct DiscoveryResponse {
+    #[serde(default)]
+    pub matches: Vec<Match>,
+    pub track: Option<Track>,
+    pub tagid: Option<String>,
+    pub timestamp: Option<u64>,
+    pub timezone: Option<String>,
+    /// How long Shazam wants us to wait before trying again, usually sent along with no matches.
+    pub retryms: Option<u64>,
+}
+
+impl DiscoveryResponse {
+    pub fn from_json(json_object: &Value) -> Result<DiscoveryResponse, RecognitionError> {
+        serde_path_to_error::deserialize(json_object)
+            .map_err(|e| RecognitionError::MalformedResponse(Box::new(e)))
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retryms.map(Duration::from_millis)
+    }
+}
+
+/// Response to a `services/search/v4/.../search` request.
+#[derive(Deserialize, Debug)]
+pub struct SearchResponse {
+    pub tracks: Option<SearchHits>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchHits {
+    #[serde(default)]
+    pub hits: Vec<SearchHit>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchHit {
+    pub track: Track,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Match {
+    pub id: String,
+    /// Position of the signature in the track, in seconds.
+    pub offset: f64,
+    pub timeskew: Option<f64>,
+    pub frequencyskew: Option<f64>,
+}
+
+/// Combined skew at which we consider a match worthless.
+const MAX_SKEW: f64 = 0.02;
+
+impl Match {
+    /// 0-1 score derived from how much the audio had to be stretched in time
+    /// and frequency to line up with the reference. Clean matches have skews
+    /// within a few thousandths; big ones hint at a different recording.
+    pub fn confidence(&self) -> f32 {
+        let skew = self.timeskew.unwrap_or(0.0).abs() + self.frequencyskew.unwrap_or(0.0).abs();
+        (1.0 - skew / MAX_SKEW).clamp(0.0, 1.0) as f32
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Track {
+    pub key: String,
+    pub title: String,
+    /// The artist.
+    pub subtitle: String,
+    pub isrc: Option<String>,
+    pub url: Option<String>,
+    pub albumadamid: Option<String>,
+    pub images: Option<Images>,
+    pub share: Option<Share>,
+    pub hub: Option<Hub>,
+    pub genres: Option<Genres>,
+    #[serde(default)]
+    pub sections: Vec<Section>,
+}
+
+impl Track {
+    pub fn section(&self, kind: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.kind == kind)
+    }
+
+    /// Looks up one of the "Album", "Label", "Released"... entries of the SONG section.
+    pub fn metadata(&self, title: &str) -> Option<&str> {
+        self.section("SONG")?
+            .metadata
+            .iter()
+            .find(|metadatum| metadatum.title == title)
+            .map(|metadatum| metadatum.text.as_str())
+    }
+
+    pub fn lyrics(&self) -> Option<&[String]> {
+        self.section("LYRICS")
+            .map(|section| section.text.as_slice())
+            .filter(|text| !text.is_empty())
+    }
+
+    pub fn share_url(&self) -> Option<&str> {
+        self.share.as_ref().and_then(|share| share.href.as_deref()).or(self.url.as_deref())
+    }
+
+    /// First URI among the actions of a hub provider, e.g. "SPOTIFY" or "YOUTUBEMUSIC".
+    pub fn provider_uri(&self, kind: &str) -> Option<&str> {
+        self.hub.as_ref()?
+            .providers
+            .iter()
+            .find(|provider| provider.kind == kind)?
+            .actions
+            .iter()
+            .find_map(|action| action.uri.as_deref())
+    }
+
+    /// Apple Music is the hub itself, so its link lives in the hub options rather than the providers.
+    pub fn apple_music_uri(&self) -> Option<&str> {
+        self.hub.as_ref()?
+            .options
+            .iter()
+            .filter(|option| option.providername.as_deref() == Some("applemusic"))
+            .flat_map(|option| &option.actions)
+            .find_map(|action| action.uri.as_deref().filter(|uri| uri.starts_with("http")))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Images {
+    pub background: Option<String>,
+    pub coverart: Option<String>,
+    pub coverarthq: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Share {
+    pub subject: Option<String>,
+    pub text: Option<String>,
+    pub href: Option<String>,
+    pub image: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Hub {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub displayname: Option<String>,
+    pub image: Option<String>,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    #[serde(default)]
+    pub options: Vec<HubOption>,
+    #[serde(default)]
+    pub providers: Vec<Provider>,
+    pub explicit: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HubOption {
+    pub caption: Option<String>,
+    pub providername: Option<String>,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Provider {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub caption: Option<String>,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Action {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: Option<String>,
+    pub id: Option<String>,
+    pub uri: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Genres {
+    pub primary: Option<String>,
+}
+
+/// One of the tabs of the track page: SONG, LYRICS, VIDEO, ARTIST, RELATED...
+/// Fields only present on some kinds of section are optional or default to empty.
+#[derive(Deserialize, Debug)]
+pub struct Section {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub tabname: Option<String>,
+    /// SONG
+    #[serde(default)]
+    pub metadata: Vec<Metadatum>,
+    /// LYRICS
+    #[serde(default)]
+    pub text: Vec<String>,
+    pub footer: Option<String>,
+    /// RELATED
+    pub url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Metadatum {
+    pub title: String,
+    pub text: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written to the shape of real discovery/v5 responses, with made-up tracks
+    // and a few of the fields we don't read left in to make sure they're ignored.
+    fn fixture(name: &str) -> Value {
+        let path = format!("{}/tests/fixtures/{}.json", env!("CARGO_MANIFEST_DIR"), name);
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parses_a_match() {
+        let response = DiscoveryResponse::from_json(&fixture("discovery_match")).unwrap();
+
+        assert_eq!(response.matches.len(), 2);
+        assert_eq!(response.matches[0].id, "612846392");
+        assert!((response.matches[0].offset - 73.412734375).abs() < 1e-9);
+        assert!(response.matches[0].confidence() > 0.95);
+        assert!(response.matches[1].confidence() < 0.1);
+        assert_eq!(response.tagid.as_deref(), Some("A1B2C3D4-1111-2222-3333-444455556666"));
+        assert_eq!(response.retry_after(), None);
+
+        let track = response.track.unwrap();
+        assert_eq!(track.key, "612846392");
+        assert_eq!(track.title, "Paper Lanterns");
+        assert_eq!(track.subtitle, "The Quiet Harbour");
+        assert_eq!(track.isrc.as_deref(), Some("GBXYZ2100017"));
+        assert_eq!(track.albumadamid.as_deref(), Some("1589473001"));
+        assert_eq!(track.metadata("Album"), Some("Low Tide Letters"));
+        assert_eq!(track.metadata("Released"), Some("2021"));
+        assert_eq!(track.metadata("Producer"), None);
+        assert_eq!(track.share_url(), Some("https://www.shazam.com/track/612846392/paper-lanterns"));
+        assert!(track.images.as_ref().unwrap().coverart.as_deref().unwrap().ends_with("cover.jpg/400x400cc.jpg"));
+        assert_eq!(track.genres.as_ref().unwrap().primary.as_deref(), Some("Alternative"));
+        assert_eq!(track.lyrics(), None);
+    }
+
+    #[test]
+    fn parses_no_match() {
+        let response = DiscoveryResponse::from_json(&fixture("discovery_no_match")).unwrap();
+
+        assert!(response.matches.is_empty());
+        assert!(response.track.is_none());
+        assert_eq!(response.retry_after(), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn parses_lyrics_and_providers() {
+        let response = DiscoveryResponse::from_json(&fixture("discovery_lyrics_hub")).unwrap();
+        let track = response.track.unwrap();
+
+        let lyrics = track.lyrics().unwrap();
+        assert_eq!(lyrics.len(), 4);
+        assert_eq!(lyrics[0], "First line of the first verse");
+        assert_eq!(lyrics[2], "");
+        assert!(track.section("LYRICS").unwrap().footer.as_deref().unwrap().contains("musixmatch"));
+
+        assert_eq!(track.provider_uri("SPOTIFY"), Some("spotify:search:Northbound%20Static%20Mira%20Calloway"));
+        assert_eq!(
+            track.provider_uri("YOUTUBEMUSIC"),
+            Some("https://music.youtube.com/search?q=Northbound%20Static%20Mira%20Calloway&feature=shazam")
+        );
+        assert_eq!(track.provider_uri("DEEZER"), None);
+        assert_eq!(
+            track.apple_music_uri(),
+            Some("https://music.apple.com/us/album/northbound-static/1499920990?i=1499921004&mttnagencyid=s2n")
+        );
+        assert_eq!(track.metadata("Album"), Some("Signal Fires"));
+    }
+
+    #[test]
+    fn missing_fields_report_their_path() {
+        let mut json = fixture("discovery_match");
+        json["track"].as_object_mut().unwrap().remove("title");
+
+        let Err(RecognitionError::MalformedResponse(e)) = DiscoveryResponse::from_json(&json) else {
+            panic!("expected a malformed response");
+        };
+        let e = e.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>().unwrap();
+        // A missing field is reported at the struct that lacks it
+        assert_eq!(e.path().to_string(), "track");
+        assert!(e.to_string().contains("missing field `title`"), "{}", e);
+
+        let mut json = fixture("discovery_lyrics_hub");
+        json["track"]["hub"]["providers"][1].as_object_mut().unwrap().remove("type");
+
+        let Err(RecognitionError::MalformedResponse(e)) = DiscoveryResponse::from_json(&json) else {
+            panic!("expected a malformed response");
+        };
+        assert!(e.to_string().starts_with("track.hub.providers[1]: missing field `type`"), "{}", e);
+    }
+}
@@ -1,15 +1,22 @@
 use crate::shazam::fingerprinting::signature_format::DecodedSignature;
 
-use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 /// This module contains code used from message-based communication between threads.
 
+#[derive(Clone)]
 pub struct SongRecognizedMessage {
     pub artist_name: String,
     pub album_name: Option<String>,
     pub song_name: String,
     pub cover_image: Option<String>,
+    /// Largest variant of the cover in the local cache, once downloaded.
+    pub local_cover: Option<PathBuf>,
     pub track_seek: Option<f32>,
+    /// Every candidate Shazam returned, best first. Empty for extrapolated matches.
+    pub matches: Vec<MatchCandidate>,
     pub signature: Box<DecodedSignature>,
 
     // Used only in the CSV export for now:
@@ -17,6 +24,76 @@ pub struct SongRecognizedMessage {
     pub release_year: Option<String>,
     pub genre: Option<String>,
 
+    pub isrc: Option<String>,
+    pub label: Option<String>,
+    pub share_url: Option<String>,
+    pub links: TrackLinks,
+    /// Apple Music ID of the album the track is from.
+    pub album_id: Option<String>,
+    /// One entry per line.
+    pub lyrics: Option<Vec<String>>,
+    pub musicbrainz: Option<MusicBrainzInfo>,
+    /// Length of the track, from the album tracklist or MusicBrainz.
+    pub duration: Option<Duration>,
+
     pub shazam_json: String,
+    /// When the last sample of the signature was captured.
     pub timestamp: SystemTime,
-}
\ No newline at end of file
+    /// Set when the match was extrapolated from the previous one instead of coming from the network.
+    pub extrapolated: bool,
+}
+
+impl SongRecognizedMessage {
+    /// Confidence of the best candidate, if this came from the network.
+    pub fn confidence(&self) -> Option<f32> {
+        self.matches.first().map(|candidate| candidate.confidence)
+    }
+
+    /// When the track started playing, going by the seek position at the start of the signature.
+    pub fn track_started_at(&self) -> Option<SystemTime> {
+        let seek = self.track_seek?;
+        let signature_length = self.signature.number_samples as f32 / self.signature.sample_rate_hz as f32;
+
+        self.timestamp.checked_sub(Duration::from_secs_f32(seek.max(0.0) + signature_length))
+    }
+
+    /// When the track should end, if we know how long it is.
+    pub fn track_ends_at(&self) -> Option<SystemTime> {
+        Some(self.track_started_at()? + self.duration?)
+    }
+}
+
+#[derive(Clone)]
+pub struct MatchCandidate {
+    /// Shazam ID of the candidate, which is usually its track key.
+    pub id: String,
+    pub offset: f32,
+    pub time_skew: Option<f32>,
+    pub frequency_skew: Option<f32>,
+    pub confidence: f32,
+}
+
+/// Where the track can be played.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct TrackLinks {
+    pub apple_music: Option<String>,
+    pub spotify: Option<String>,
+    pub youtube_music: Option<String>,
+}
+
+/// Where the track sits in the MusicBrainz database.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MusicBrainzInfo {
+    pub recording_id: String,
+    pub release_id: String,
+    /// As printed on the release, so e.g. "A2" for vinyl.
+    pub track_number: Option<String>,
+    pub disc_number: Option<u32>,
+    /// "CD", "12\" Vinyl", ...
+    pub medium_format: Option<String>,
+    /// `YYYY`, `YYYY-MM` or `YYYY-MM-DD`.
+    pub release_date: Option<String>,
+    pub label: Option<String>,
+    #[serde(default)]
+    pub length_ms: Option<u64>,
+}
@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+
+#[derive(Debug)]
+pub enum RecognitionError {
+    /// Couldn't reach the server at all.
+    Network(reqwest::Error),
+    Timeout(reqwest::Error),
+    /// The server answered with an unexpected status code.
+    HttpStatus { status: StatusCode, source: Option<reqwest::Error> },
+    /// The response couldn't be parsed or was missing something we need.
+    MalformedResponse(Box<dyn Error + Send + Sync>),
+    /// The server understood the request but didn't find the song.
+    NoMatch { retry_after: Option<Duration> },
+    /// HTTP 429 or equivalent.
+    Throttled { retry_after: Option<Duration> },
+    /// We've used up the `budget` ourselves, nothing was sent.
+    OverBudget { retry_after: Duration },
+    SignatureEncoding(std::io::Error),
+    /// The backend reported an error of its own, e.g. a bad API key.
+    Api { code: i64, message: String },
+}
+
+impl RecognitionError {
+    /// For a response whose status we didn't expect.
+    pub fn from_status(response: &reqwest::Response) -> RecognitionError {
+        RecognitionError::HttpStatus {
+            status: response.status(),
+            source: response.error_for_status_ref().err(),
+        }
+    }
+
+    /// Whether the request never made it to the server, so it's worth trying again later.
+    pub fn is_offline(&self) -> bool {
+        matches!(self, RecognitionError::Network(_) | RecognitionError::Timeout(_))
+    }
+
+    /// Whether the same request might well work later: we're offline, or the
+    /// server is having trouble (5xx, or sending back garbage during an outage).
+    pub fn is_temporary(&self) -> bool {
+        match self {
+            RecognitionError::HttpStatus { status, .. } => status.is_server_error(),
+            RecognitionError::MalformedResponse(_) => true,
+            e => e.is_offline(),
+        }
+    }
+}
+
+impl fmt::Display for RecognitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecognitionError::Network(e) => write!(f, "Failed to send request: {}", e),
+            RecognitionError::Timeout(e) => write!(f, "Request timed out: {}", e),
+            RecognitionError::HttpStatus { status, .. } => write!(f, "Unexpected response: {}", status),
+            RecognitionError::MalformedResponse(e) => write!(f, "Malformed response: {}", e),
+            RecognitionError::NoMatch { .. } => write!(f, "No match for this song"),
+            RecognitionError::Throttled { retry_after: Some(retry_after) } => write!(f, "Throttled, retry in {}s", retry_after.as_secs()),
+            RecognitionError::Throttled { retry_after: None } => write!(f, "Throttled"),
+            RecognitionError::OverBudget { retry_after } => write!(f, "Out of requests, the next one is allowed in {}s", retry_after.as_secs()),
+            RecognitionError::SignatureEncoding(e) => write!(f, "Failed to encode signature: {}", e),
+            RecognitionError::Api { code, message } => write!(f, "API error {}: {}", code, message),
+        }
+    }
+}
+
+impl Error for RecognitionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RecognitionError::Network(e) | RecognitionError::Timeout(e) => Some(e),
+            RecognitionError::HttpStatus { source, .. } => source.as_ref().map(|e| e as &(dyn Error + 'static)),
+            RecognitionError::MalformedResponse(e) => Some(e.as_ref()),
+            RecognitionError::SignatureEncoding(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for RecognitionError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            RecognitionError::Timeout(e)
+        } else if e.is_decode() {
+            RecognitionError::MalformedResponse(Box::new(e))
+        } else if let Some(status) = e.status() {
+            RecognitionError::HttpStatus { status, source: Some(e) }
+        } else {
+            RecognitionError::Network(e)
+        }
+    }
+}
+
+impl From<serde_json::Error> for RecognitionError {
+    fn from(e: serde_json::Error) -> Self {
+        RecognitionError::MalformedResponse(Box::new(e))
+    }
+}
@@ -1,5 +1,7 @@
 pub mod algorithm;
+#[cfg(feature = "shazam-client")]
 pub mod communication;
 pub mod hanning;
 pub mod signature_format;
-pub mod user_agent;
\ No newline at end of file
+#[cfg(feature = "shazam-client")]
+pub mod user_agent;
@@ -4,13 +4,19 @@ use std::collections::HashMap;
 use crate::shazam::fingerprinting::hanning::HANNING_WINDOW_2048_MULTIPLIERS;
 use crate::shazam::fingerprinting::signature_format::{DecodedSignature, FrequencyBand, FrequencyPeak};
 #[cfg(feature = "ffmpeg")]
-use crate::utils::ffmpeg_wrapper::decode_with_ffmpeg;
+use crate::utils::ffmpeg_wrapper::{decode_with_ffmpeg, FfmpegError};
+#[cfg(feature = "ffmpeg")]
+use std::path::Path;
 
 
+/// Builds Shazam signatures from 16 kHz mono audio.
 pub struct SignatureGenerator {
 
     // Used when processing input:
 
+    /// Samples fed in since the last full chunk of 128.
+    pending_samples: Vec<i16>,
+
     ring_buffer_of_samples: Vec<i16>,
     /// Ring buffer.
     ring_buffer_of_samples_index: usize,
@@ -33,9 +39,17 @@ pub struct SignatureGenerator {
     signature: DecodedSignature,
 }
 
+impl Default for SignatureGenerator {
+    fn default() -> Self {
+        SignatureGenerator::new()
+    }
+}
+
 impl SignatureGenerator {
-    pub fn make_signature_from_buffer(s16_mono_16khz_buffer: &[i16]) -> DecodedSignature {
-        let mut this = SignatureGenerator {
+    pub fn new() -> SignatureGenerator {
+        SignatureGenerator {
+            pending_samples: Vec::with_capacity(128),
+
             ring_buffer_of_samples: vec![0i16; 2048],
             ring_buffer_of_samples_index: 0,
 
@@ -53,24 +67,97 @@ impl SignatureGenerator {
 
             signature: DecodedSignature {
                 sample_rate_hz: 16000,
-                number_samples: s16_mono_16khz_buffer.len() as u32,
+                number_samples: 0,
                 frequency_band_to_sound_peaks: HashMap::new(),
             },
-        };
+        }
+    }
+
+    /// Fingerprints a whole buffer of 16 kHz mono samples at once.
+    pub fn make_signature_from_buffer(s16_mono_16khz_buffer: &[i16]) -> DecodedSignature {
+        let mut this = SignatureGenerator::new();
+        this.feed(s16_mono_16khz_buffer);
+        this.finish()
+    }
+
+    /// Decodes anything ffmpeg can read, video included, and makes one
+    /// signature per `window_secs` of its audio, in order.
+    #[cfg(feature = "ffmpeg")]
+    pub fn make_signatures_from_file(path: &Path, window_secs: u32) -> Result<Vec<DecodedSignature>, FfmpegError> {
+        let window_samples = window_secs.max(1) * 16_000;
+        let mut signatures = vec![];
+        let mut generator = SignatureGenerator::new();
+
+        decode_with_ffmpeg(path, |mut samples| {
+            while !samples.is_empty() {
+                let wanted = (window_samples - generator.number_samples()) as usize;
+                let (now, later) = samples.split_at(wanted.min(samples.len()));
+                generator.feed(now);
+                samples = later;
+
+                if generator.number_samples() == window_samples {
+                    signatures.push(std::mem::take(&mut generator).finish());
+                }
+            }
+        })?;
+
+        // Whatever's left at the end, unless it's too short to be worth sending
+        if generator.number_samples() >= window_samples / 4 {
+            signatures.push(generator.finish());
+        }
+
+        if signatures.is_empty() {
+            return Err(FfmpegError::NoAudio);
+        }
 
-        for chunk in s16_mono_16khz_buffer.chunks_exact(128) {
-            this.do_fft(chunk);
+        Ok(signatures)
+    }
 
-            this.do_peak_spreading();
+    /// Adds more samples to the signature. They don't need to line up with the
+    /// 128 sample chunks the FFT works on, leftovers are kept for the next call.
+    pub fn feed(&mut self, s16_mono_16khz_buffer: &[i16]) {
+        self.signature.number_samples += s16_mono_16khz_buffer.len() as u32;
 
-            this.num_spread_ffts_done += 1;
+        let mut samples = s16_mono_16khz_buffer;
+        if !self.pending_samples.is_empty() {
+            let missing = (128 - self.pending_samples.len()).min(samples.len());
+            self.pending_samples.extend_from_slice(&samples[..missing]);
+            samples = &samples[missing..];
 
-            if this.num_spread_ffts_done >= 46 {
-                this.do_peak_recognition();
+            if self.pending_samples.len() < 128 {
+                return;
             }
+
+            let chunk = std::mem::take(&mut self.pending_samples);
+            self.process_chunk(&chunk);
+        }
+
+        let mut chunks = samples.chunks_exact(128);
+        for chunk in &mut chunks {
+            self.process_chunk(chunk);
         }
+        self.pending_samples.extend_from_slice(chunks.remainder());
+    }
 
-        this.signature
+    /// Samples fed so far.
+    pub fn number_samples(&self) -> u32 {
+        self.signature.number_samples
+    }
+
+    pub fn finish(self) -> DecodedSignature {
+        self.signature
+    }
+
+    fn process_chunk(&mut self, chunk: &[i16]) {
+        self.do_fft(chunk);
+
+        self.do_peak_spreading();
+
+        self.num_spread_ffts_done += 1;
+
+        if self.num_spread_ffts_done >= 46 {
+            self.do_peak_recognition();
+        }
     }
 
     fn do_fft(&mut self, s16_mono_16khz_buffer: &[i16]) {
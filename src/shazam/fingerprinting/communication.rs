@@ -1,58 +1,326 @@
 use serde_json::{json, Value};
-use reqwest::header::HeaderMap;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LANGUAGE, RETRY_AFTER, USER_AGENT};
+use reqwest::{Certificate, Client, Proxy, RequestBuilder, StatusCode};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use std::time::Duration;
 use rand::seq::SliceRandom;
 use uuid::Uuid;
 
+use crate::config::{Config, HttpConfig, IdentityConfig};
+use crate::shazam::core::album::{Album, AlbumResponse};
+use crate::shazam::core::budget::RequestBudget;
+use crate::shazam::core::response::{SearchResponse, Track};
+use crate::shazam::error::RecognitionError;
 use crate::shazam::fingerprinting::signature_format::DecodedSignature;
 use crate::shazam::fingerprinting::user_agent::USER_AGENTS;
 
-pub async fn recognize_song_from_signature(signature: &DecodedSignature) -> Result<Value, String>  {
-    
-    let timestamp_ms = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).or(Err("Failed to get timestamp"))?.as_millis();
-    
-    let post_data = json!({
-        "geolocation": {
-            "altitude": 300,
-            "latitude": 45,
-            "longitude": 2
-        },
-        "signature": {
-            "samplems": (signature.number_samples as f32 / signature.sample_rate_hz as f32 * 1000.) as u32,
+/// Talks to the Shazam API. Holds a single pooled HTTP client, so it's cheap to clone and share.
+#[derive(Clone)]
+pub struct Recognizer {
+    client: Client,
+    base_url: String,
+    web_base_url: String,
+    max_attempts: u32,
+    retry_base: Duration,
+    identity: IdentityConfig,
+    user_agent: Option<HeaderValue>,
+    content_language: HeaderValue,
+    budget: Arc<Mutex<RequestBudget>>,
+}
+
+impl Recognizer {
+    pub fn new(config: &Config) -> Result<Recognizer, String> {
+        let mut identity = config.identity.clone();
+        if identity.installation_id.is_none() && identity.persist_installation_id {
+            identity.installation_id = Some(load_installation_id(&config.data_dir)?);
+        }
+
+        let user_agent = match &identity.user_agent {
+            Some(user_agent) => Some(user_agent.parse().map_err(|_| format!("Invalid user agent: {}", user_agent))?),
+            None => None,
+        };
+        let content_language = identity.content_language.parse().map_err(|_| format!("Invalid content language: {}", identity.content_language))?;
+
+        let budget = Arc::new(Mutex::new(RequestBudget::load(config)));
+
+        let config = &config.http;
+
+        Ok(Recognizer {
+            client: build_client(config)?,
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            web_base_url: config.web_base_url.trim_end_matches('/').to_string(),
+            max_attempts: config.max_attempts.max(1),
+            retry_base: Duration::from_millis(config.retry_base_ms),
+            identity,
+            user_agent,
+            content_language,
+            budget,
+        })
+    }
+
+    /// The underlying HTTP client, for fetching other things with the same proxy and TLS settings.
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    /// How long to wait between recognitions for the request budget to last, if it's running low.
+    pub fn pacing(&self) -> Option<Duration> {
+        self.budget.lock().unwrap().pacing()
+    }
+
+    /// `timestamp` is when the audio was captured, which might be a while ago for queued signatures.
+    pub async fn recognize_song_from_signature(&self, signature: &DecodedSignature, timestamp: SystemTime) -> Result<Value, RecognitionError>  {
+        if let Err(retry_after) = self.budget.lock().unwrap().take() {
+            return Err(RecognitionError::OverBudget { retry_after });
+        }
+
+        let timestamp_ms = timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis();
+        
+        let mut post_data = json!({
+            "signature": {
+                "samplems": (signature.number_samples as f32 / signature.sample_rate_hz as f32 * 1000.) as u32,
+                "timestamp": timestamp_ms as u32,
+                "uri": signature.encode_to_uri().map_err(RecognitionError::SignatureEncoding)?
+            },
             "timestamp": timestamp_ms as u32,
-            "uri": signature.encode_to_uri().or(Err("Failed to encode timestamp"))?
-        },
-        "timestamp": timestamp_ms as u32,
-        "timezone": "Europe/London"
-    });
-
-    let uuid_1 = Uuid::new_v4().hyphenated().to_string().to_uppercase();
-    let uuid_2 = Uuid::new_v4().hyphenated().to_string();
-
-    let url = format!("https://amp.shazam.com/discovery/v5/en/US/android/-/tag/{}/{}", uuid_1, uuid_2);
-
-    let mut headers = HeaderMap::new();
-    
-    headers.insert("User-Agent", USER_AGENTS.choose(&mut rand::thread_rng()).unwrap().parse().or(Err("Failed to set User-Agent header"))?);
-    headers.insert("Content-Language", "en_US".parse().or(Err("Failed to set Content-Language header"))?);
-
-    let client = reqwest::Client::new();
-    let response = client.post(&url)
-        .timeout(Duration::from_secs(20))
-        .query(&[
-            ("sync", "true"),
-            ("webv3", "true"),
-            ("sampling", "true"),
-            ("connected", ""),
-            ("shazamapiversion", "v3"),
-            ("sharehub", "true"),
-            ("video", "v3")
-        ])
-        .headers(headers)
-        .json(&post_data)
-        .send().await.or(Err("Failed to send request"))?;
-    
-    Ok(response.json().await.or(Err("Failed to parse JSON"))?)
-    
-}
\ No newline at end of file
+            "timezone": self.identity.timezone
+        });
+
+        if let Some(geolocation) = &self.identity.geolocation {
+            post_data["geolocation"] = json!(geolocation);
+        }
+
+        let uuid_1 = match &self.identity.installation_id {
+            Some(installation_id) => installation_id.to_uppercase(),
+            None => Uuid::new_v4().hyphenated().to_string().to_uppercase(),
+        };
+        let uuid_2 = Uuid::new_v4().hyphenated().to_string();
+
+        let url = format!("{}/discovery/v5/{}/{}/android/-/tag/{}/{}", self.base_url, self.identity.language, self.identity.country, uuid_1, uuid_2);
+
+        let request = self.client.post(&url)
+            .query(&[
+                ("sync", "true"),
+                ("webv3", "true"),
+                ("sampling", "true"),
+                ("connected", ""),
+                ("shazamapiversion", "v3"),
+                ("sharehub", "true"),
+                ("video", "v3")
+            ])
+            .json(&post_data);
+
+        self.send(request).await
+    }
+
+    /// Fetches full details for a track, e.g. one we only have the key of.
+    pub async fn get_track(&self, track_key: &str) -> Result<Track, RecognitionError> {
+        let url = format!("{}/discovery/v5/{}/{}/web/-/track/{}", self.web_base_url, self.identity.language, self.identity.country, track_key);
+
+        let request = self.client.get(&url)
+            .query(&[
+                ("shazamapiversion", "v3"),
+                ("video", "v3")
+            ]);
+
+        serde_path_to_error::deserialize(&self.send(request).await?)
+            .map_err(|e| RecognitionError::MalformedResponse(Box::new(e)))
+    }
+
+    /// Free text search over the catalogue.
+    pub async fn search_tracks(&self, term: &str, limit: u32) -> Result<Vec<Track>, RecognitionError> {
+        let url = format!("{}/services/search/v4/{}/{}/web/search", self.web_base_url, self.identity.language, self.identity.country);
+
+        let request = self.client.get(&url)
+            .query(&[
+                ("term", term),
+                ("numResults", &limit.to_string()),
+                ("offset", "0"),
+                ("types", "songs")
+            ]);
+
+        let response: SearchResponse = serde_path_to_error::deserialize(&self.send(request).await?)
+            .map_err(|e| RecognitionError::MalformedResponse(Box::new(e)))?;
+
+        Ok(response.tracks.map(|tracks| tracks.hits.into_iter().map(|hit| hit.track).collect()).unwrap_or_default())
+    }
+
+    /// Fetches an album's tracklist from the Apple Music catalogue.
+    pub async fn get_album(&self, album_id: &str) -> Result<Album, RecognitionError> {
+        let url = format!("{}/services/amapi/v1/catalog/{}/albums/{}", self.web_base_url, self.identity.country, album_id);
+
+        let response: AlbumResponse = serde_path_to_error::deserialize(&self.send(self.client.get(&url)).await?)
+            .map_err(|e| RecognitionError::MalformedResponse(Box::new(e)))?;
+
+        response.into_album()
+    }
+
+    /// Sends a request with our headers, retrying transport and server errors.
+    async fn send(&self, request: RequestBuilder) -> Result<Value, RecognitionError> {
+        let user_agent = match &self.user_agent {
+            Some(user_agent) => user_agent.clone(),
+            None => HeaderValue::from_static(USER_AGENTS.choose(&mut rand::thread_rng()).unwrap()),
+        };
+
+        let mut headers = HeaderMap::new();
+        
+        headers.insert(USER_AGENT, user_agent);
+        headers.insert(CONTENT_LANGUAGE, self.content_language.clone());
+
+        let request = request.headers(headers);
+
+        let mut delay = self.retry_base;
+        let mut attempt = 1;
+        let response = loop {
+            // Only the body is cloned here, which is always in memory
+            let res = request.try_clone().unwrap().send().await;
+
+            match res {
+                Ok(response) if !response.status().is_server_error() => break response,
+                Ok(response) if attempt >= self.max_attempts => {
+                    return Err(RecognitionError::from_status(&response));
+                }
+                Err(e) if attempt >= self.max_attempts => return Err(e.into()),
+                _ => {}
+            }
+
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        };
+
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response.headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs);
+
+            return Err(RecognitionError::Throttled { retry_after });
+        }
+
+        if !response.status().is_success() {
+            return Err(RecognitionError::from_status(&response));
+        }
+
+        Ok(response.json().await?)
+    }
+}
+
+/// An HTTP client with the timeouts, proxy and CA certificates from the config.
+pub fn build_client(config: &HttpConfig) -> Result<Client, String> {
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs));
+
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(Proxy::all(proxy).map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?);
+    }
+
+    if let Some(ca_bundle) = &config.ca_bundle {
+        let pem = fs::read(ca_bundle).map_err(|e| format!("Failed to read CA bundle {}: {}", ca_bundle.display(), e))?;
+        for certificate in Certificate::from_pem_bundle(&pem).map_err(|e| format!("Invalid CA bundle {}: {}", ca_bundle.display(), e))? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    builder.build().map_err(|e| format!("Failed to build HTTP client: {}", e))
+}
+
+const INSTALLATION_ID_FILE_NAME: &str = "installation_id";
+
+fn load_installation_id(data_dir: &Path) -> Result<String, String> {
+    let path = data_dir.join(INSTALLATION_ID_FILE_NAME);
+
+    if let Ok(installation_id) = fs::read_to_string(&path) {
+        return Ok(installation_id.trim().to_string());
+    }
+
+    let installation_id = Uuid::new_v4().hyphenated().to_string();
+
+    fs::create_dir_all(data_dir)
+        .and_then(|_| fs::write(&path, &installation_id))
+        .map_err(|e| format!("Failed to save installation ID to {}: {}", path.display(), e))?;
+
+    Ok(installation_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{temp_dir, Stub};
+
+    fn recognizer(stub: &Stub, name: &str) -> Recognizer {
+        let mut config = Config {
+            data_dir: temp_dir(name),
+            ..Config::default()
+        };
+        config.http.web_base_url = format!("{}/", stub.url);
+        config.http.max_attempts = 1;
+        Recognizer::new(&config).unwrap()
+    }
+
+    /// The `track` object out of a recorded-shape discovery response, which is
+    /// what the track endpoint returns on its own.
+    fn track_json() -> String {
+        let path = format!("{}/tests/fixtures/discovery_match.json", env!("CARGO_MANIFEST_DIR"));
+        let response: Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        response["track"].to_string()
+    }
+
+    #[tokio::test]
+    async fn gets_a_track() {
+        let track = track_json();
+        let stub = Stub::start(move |_| (200, track.clone())).await;
+
+        let track = recognizer(&stub, "get_track").get_track("612846392").await.unwrap();
+        assert_eq!(track.title, "Paper Lanterns");
+        assert_eq!(track.subtitle, "The Quiet Harbour");
+        assert_eq!(track.metadata("Album"), Some("Low Tide Letters"));
+
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(requests[0].target, "/discovery/v5/en/US/web/-/track/612846392?shazamapiversion=v3&video=v3");
+        assert_eq!(requests[0].header("content-language"), Some("en_US"));
+        assert!(requests[0].header("user-agent").is_some());
+        assert!(requests[0].body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn searches() {
+        let hits = format!(r#"{{"tracks": {{"hits": [{{"track": {}}}, {{"track": {}}}]}}}}"#, track_json(), track_json());
+        let stub = Stub::start(move |request| match request.target.contains("term=paper+lanterns") {
+            true => (200, hits.clone()),
+            // Shazam sends an empty object when nothing matches
+            false => (200, "{}".to_string()),
+        })
+        .await;
+        let recognizer = recognizer(&stub, "search_tracks");
+
+        let tracks = recognizer.search_tracks("paper lanterns", 2).await.unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].key, "612846392");
+
+        assert!(recognizer.search_tracks("nothing like it", 5).await.unwrap().is_empty());
+
+        let requests = stub.requests();
+        assert!(requests[0].target.starts_with("/services/search/v4/en/US/web/search?"));
+        assert!(requests[0].target.contains("numResults=2"));
+        assert!(requests[0].target.contains("types=songs"));
+    }
+
+    #[tokio::test]
+    async fn reports_missing_tracks() {
+        let stub = Stub::start(|_| (404, "{}".to_string())).await;
+
+        match recognizer(&stub, "track_404").get_track("1").await {
+            Err(RecognitionError::HttpStatus { status, .. }) => assert_eq!(status, StatusCode::NOT_FOUND),
+            other => panic!("expected a 404, got {:?}", other.map(|track| track.key)),
+        }
+    }
+}
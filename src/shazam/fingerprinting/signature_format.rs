@@ -1,13 +1,14 @@
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc32fast::Hasher;
 use std::cmp::Ordering;
-use std::collections::HashMap;
-use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
 
 const DATA_URI_PREFIX: &str = "data:audio/vnd.shazam.sig;base64,";
 
+#[derive(Clone)]
 pub struct FrequencyPeak {
     pub fft_pass_number: u32,
     pub peak_magnitude: u16,
@@ -64,6 +65,8 @@ impl PartialOrd for FrequencyBand {
     }
 }
 
+#[derive(Clone)]
+/// A Shazam signature: the frequency peaks found in a piece of audio, by band.
 pub struct DecodedSignature {
     pub sample_rate_hz: u32,
     pub number_samples: u32,
@@ -71,6 +74,7 @@ pub struct DecodedSignature {
 }
 
 impl DecodedSignature {
+    /// Shazam's binary signature format.
     pub fn encode_to_binary(&self) -> Result<Vec<u8>, std::io::Error> {
         let mut cursor = Cursor::new(vec![]);
 
@@ -160,8 +164,228 @@ impl DecodedSignature {
         Ok(cursor.into_inner())
     }
 
+    /// The binary format as a `data:audio/vnd.shazam.sig;base64,` URI, as sent to the API.
     pub fn encode_to_uri(&self) -> Result<String, std::io::Error> {
         let res = BASE64_STANDARD.encode(self.encode_to_binary()?);
         Ok(format!("{}{}", DATA_URI_PREFIX, res))
     }
+
+    /// Reverse of `encode_to_binary`, checking the header and CRC.
+    pub fn decode_from_binary(data: &[u8]) -> Result<DecodedSignature, std::io::Error> {
+        if data.len() < 48 + 8 {
+            return Err(Error::new(ErrorKind::InvalidData, "Signature is too short"));
+        }
+
+        let mut cursor = Cursor::new(data);
+
+        if cursor.read_u32::<LittleEndian>()? != 0xcafe2580 {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid magic1"));
+        }
+
+        let crc32 = cursor.read_u32::<LittleEndian>()?;
+        let mut hasher = Hasher::new();
+        hasher.update(&data[8..]);
+        if hasher.finalize() != crc32 {
+            return Err(Error::new(ErrorKind::InvalidData, "CRC32 mismatch"));
+        }
+
+        let size_minus_header = cursor.read_u32::<LittleEndian>()?;
+        if size_minus_header as usize != data.len() - 48 {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid size"));
+        }
+
+        if cursor.read_u32::<LittleEndian>()? != 0x94119c00 {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid magic2"));
+        }
+
+        cursor.seek(SeekFrom::Current(3 * 4))?; // void1
+
+        let sample_rate_hz = match cursor.read_u32::<LittleEndian>()? >> 27 {
+            1 => 8000,
+            2 => 11025,
+            3 => 16000,
+            4 => 32000,
+            5 => 44100,
+            6 => 48000,
+            _ => {
+                return Err(Error::new(ErrorKind::InvalidData, "Invalid sample rate"));
+            }
+        }; // shifted_sample_rate_id
+
+        cursor.seek(SeekFrom::Current(2 * 4))?; // void2
+
+        let number_samples = cursor
+            .read_u32::<LittleEndian>()?
+            .checked_sub((sample_rate_hz as f32 * 0.24) as u32)
+            .ok_or(Error::new(ErrorKind::InvalidData, "Invalid number of samples"))?; // number_samples_plus_divided_sample_rate
+
+        cursor.seek(SeekFrom::Current(4))?; // fixed_value
+
+        if cursor.read_u32::<LittleEndian>()? != 0x40000000 {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid TLV header"));
+        }
+        cursor.seek(SeekFrom::Current(4))?; // size_minus_header
+
+        let mut frequency_band_to_sound_peaks = HashMap::new();
+
+        while (cursor.position() as usize) < data.len() {
+            let frequency_band = match cursor.read_u32::<LittleEndian>()?.wrapping_sub(0x60030040) {
+                0 => FrequencyBand::_250_520,
+                1 => FrequencyBand::_520_1450,
+                2 => FrequencyBand::_1450_3500,
+                3 => FrequencyBand::_3500_5500,
+                _ => {
+                    return Err(Error::new(ErrorKind::InvalidData, "Invalid frequency band"));
+                }
+            };
+
+            let size = cursor.read_u32::<LittleEndian>()? as usize;
+            let mut peaks_buffer = vec![0u8; size];
+            cursor.read_exact(&mut peaks_buffer)?;
+            cursor.seek(SeekFrom::Current((4 - size as i64 % 4) % 4))?; // padding
+
+            let mut peaks_cursor = Cursor::new(&peaks_buffer);
+            let mut frequency_peaks = vec![];
+            let mut fft_pass_number = 0;
+
+            while (peaks_cursor.position() as usize) < size {
+                let fft_pass_offset = peaks_cursor.read_u8()?;
+
+                if fft_pass_offset == 0xff {
+                    fft_pass_number = peaks_cursor.read_u32::<LittleEndian>()?;
+                    continue;
+                }
+
+                fft_pass_number += fft_pass_offset as u32;
+
+                frequency_peaks.push(FrequencyPeak {
+                    fft_pass_number,
+                    peak_magnitude: peaks_cursor.read_u16::<LittleEndian>()?,
+                    corrected_peak_frequency_bin: peaks_cursor.read_u16::<LittleEndian>()?,
+                    sample_rate_hz,
+                });
+            }
+
+            frequency_band_to_sound_peaks.insert(frequency_band, frequency_peaks);
+        }
+
+        Ok(DecodedSignature {
+            sample_rate_hz,
+            number_samples,
+            frequency_band_to_sound_peaks,
+        })
+    }
+
+    /// Reverse of `encode_to_uri`.
+    pub fn decode_from_uri(uri: &str) -> Result<DecodedSignature, std::io::Error> {
+        let data = uri
+            .strip_prefix(DATA_URI_PREFIX)
+            .ok_or(Error::new(ErrorKind::InvalidData, "Invalid data URI prefix"))?;
+
+        let data = BASE64_STANDARD
+            .decode(data)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        DecodedSignature::decode_from_binary(&data)
+    }
+
+    /// Pairs of nearby peaks in the same band, as (first frequency, second
+    /// frequency, time between them). Unlike the peaks themselves these don't
+    /// depend on where in the window the audio falls, so two windows of the
+    /// same recording share a good part of them (the same notes played the same
+    /// way keep coming back) while unrelated audio shares next to none.
+    pub fn landmarks(&self) -> HashSet<u64> {
+        let mut landmarks = HashSet::new();
+
+        for (band, peaks) in &self.frequency_band_to_sound_peaks {
+            let mut peaks: Vec<&FrequencyPeak> = peaks.iter().collect();
+            peaks.sort_by_key(|peak| peak.fft_pass_number);
+
+            for (i, anchor) in peaks.iter().enumerate() {
+                for peak in peaks[i + 1..].iter().take(LANDMARK_FAN_OUT) {
+                    let distance = peak.fft_pass_number - anchor.fft_pass_number;
+                    if distance > LANDMARK_MAX_DISTANCE {
+                        break;
+                    }
+
+                    // corrected_peak_frequency_bin is the FFT bin (0..1024) multiplied by 64
+                    landmarks.insert(
+                        (*band as u64) << 48
+                            | (anchor.corrected_peak_frequency_bin as u64 / 64) << 32
+                            | (peak.corrected_peak_frequency_bin as u64 / 64) << 16
+                            | distance as u64,
+                    );
+                }
+            }
+        }
+
+        landmarks
+    }
+}
+
+/// Peaks each peak is paired with, and how far ahead (in FFT passes of 128 samples) they can be.
+const LANDMARK_FAN_OUT: usize = 5;
+const LANDMARK_MAX_DISTANCE: u32 = 128;
+
+/// Fraction (0-1) of `landmarks` that also occur in `reference`, both from `DecodedSignature::landmarks`.
+pub fn landmark_overlap(reference: &HashSet<u64>, landmarks: &HashSet<u64>) -> f32 {
+    if landmarks.is_empty() {
+        return 0.0;
+    }
+
+    landmarks.intersection(reference).count() as f32 / landmarks.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheConfig;
+    use crate::shazam::fingerprinting::algorithm::SignatureGenerator;
+    use crate::testing::{noise, song, SONG_A, SONG_B};
+
+    fn overlap(reference: &[i16], window: &[i16]) -> f32 {
+        let reference = SignatureGenerator::make_signature_from_buffer(reference).landmarks();
+        let window = SignatureGenerator::make_signature_from_buffer(window).landmarks();
+        landmark_overlap(&reference, &window)
+    }
+
+    #[test]
+    fn later_windows_of_the_same_recording_overlap() {
+        let threshold = CacheConfig::default().similarity_threshold;
+        let first = song(SONG_A, 0, 12, 1);
+
+        assert!(overlap(&first, &song(SONG_A, 12, 12, 2)) > threshold * 2.0);
+        assert!(overlap(&first, &song(SONG_A, 25, 12, 3)) > threshold * 2.0);
+    }
+
+    #[test]
+    fn different_recordings_dont() {
+        let threshold = CacheConfig::default().similarity_threshold;
+        let first = song(SONG_A, 0, 12, 1);
+
+        assert!(overlap(&first, &song(SONG_B, 12, 12, 2)) < threshold / 2.0);
+        assert!(overlap(&first, &noise(12, 4)) < threshold / 2.0);
+        assert!(overlap(&noise(12, 5), &first) < threshold / 2.0);
+        assert!(overlap(&noise(12, 5), &noise(12, 4)) < threshold / 2.0);
+    }
+
+    #[test]
+    fn round_trips_through_binary() {
+        let signature = SignatureGenerator::make_signature_from_buffer(&song(SONG_A, 0, 12, 1));
+        let decoded = DecodedSignature::decode_from_uri(&signature.encode_to_uri().unwrap()).unwrap();
+
+        assert_eq!(decoded.sample_rate_hz, signature.sample_rate_hz);
+        assert_eq!(decoded.number_samples, signature.number_samples);
+        assert_eq!(decoded.frequency_band_to_sound_peaks.len(), signature.frequency_band_to_sound_peaks.len());
+        for (band, peaks) in &signature.frequency_band_to_sound_peaks {
+            let decoded_peaks = &decoded.frequency_band_to_sound_peaks[band];
+            assert_eq!(decoded_peaks.len(), peaks.len());
+            for (decoded, peak) in decoded_peaks.iter().zip(peaks) {
+                assert_eq!(decoded.fft_pass_number, peak.fft_pass_number);
+                assert_eq!(decoded.peak_magnitude, peak.peak_magnitude);
+                assert_eq!(decoded.corrected_peak_frequency_bin, peak.corrected_peak_frequency_bin);
+            }
+        }
+        assert_eq!(decoded.encode_to_binary().unwrap(), signature.encode_to_binary().unwrap());
+    }
 }
@@ -1,2 +1,4 @@
 pub mod fingerprinting;
-pub mod core;
\ No newline at end of file
+pub mod core;
+#[cfg(feature = "shazam-client")]
+pub mod error;
@@ -0,0 +1,84 @@
+pub mod console;
+#[cfg(feature = "discord")]
+pub mod discord;
+#[cfg(feature = "local-db")]
+pub mod history;
+#[cfg(feature = "scrobbler")]
+pub mod scrobbler;
+
+use std::future::Future;
+
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use crate::config::{Config, SinkKind};
+use crate::events::Event;
+
+use console::ConsoleSink;
+#[cfg(feature = "discord")]
+use discord::DiscordSink;
+#[cfg(feature = "local-db")]
+use history::HistorySink;
+#[cfg(feature = "scrobbler")]
+use scrobbler::ScrobblerSink;
+
+/// An output, fed every event from the bus on its own task.
+pub trait Sink: Send + 'static {
+    fn name(&self) -> &'static str;
+
+    fn handle(&mut self, event: &Event) -> impl Future<Output = ()> + Send;
+}
+
+pub fn spawn<S: Sink>(mut sink: S, mut events: broadcast::Receiver<Event>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    sink.handle(&event).await;
+                    if let Event::Shutdown = event {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => eprintln!("[{}] Fell behind, missed {} events", sink.name(), missed),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Starts every sink listed in the config.
+pub async fn spawn_from_config(config: &Config, events: &broadcast::Sender<Event>) -> Vec<JoinHandle<()>> {
+    let mut handles = vec![];
+
+    for kind in &config.sinks {
+        handles.push(match kind {
+            SinkKind::Console => spawn(ConsoleSink, events.subscribe()),
+            #[cfg(feature = "discord")]
+            SinkKind::Discord => match DiscordSink::new(config) {
+                Ok(sink) => spawn(sink, events.subscribe()),
+                Err(e) => {
+                    eprintln!("[DISCORD] {}, carrying on without it", e);
+                    continue;
+                }
+            },
+            #[cfg(feature = "local-db")]
+            SinkKind::History => spawn(HistorySink::new(config), events.subscribe()),
+            #[cfg(feature = "scrobbler")]
+            SinkKind::Scrobbler => match ScrobblerSink::new(config) {
+                Ok(sink) => spawn(sink, events.subscribe()),
+                Err(e) => {
+                    eprintln!("[SCROBBLER] {}, not scrobbling", e);
+                    continue;
+                }
+            },
+            #[allow(unreachable_patterns)]
+            kind => {
+                eprintln!("[SINKS] Skipping {:?} sink, this build doesn't include it", kind);
+                continue;
+            }
+        });
+    }
+
+    handles
+}
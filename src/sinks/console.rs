@@ -0,0 +1,89 @@
+use std::time::SystemTime;
+
+use crate::events::Event;
+use crate::tracker::Transition;
+
+use super::Sink;
+
+/// Prints what's going on to stdout.
+pub struct ConsoleSink;
+
+impl Sink for ConsoleSink {
+    fn name(&self) -> &'static str {
+        "CONSOLE"
+    }
+
+    async fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Recognized(song) => {
+                if let Some(seek) = song.track_seek {
+                    let verb = if song.extrapolated { "Still playing" } else { "Song recognized" };
+                    println!("{}: {} - {} @ {}:{:02}", verb, song.song_name, song.artist_name, (seek / 60.0) as u32, (seek % 60.0) as u8);
+                } else {
+                    println!("Song recognized: {} - {}", song.song_name, song.artist_name);
+                }
+                if song.extrapolated {
+                    return;
+                }
+
+                if let Some(label) = &song.label {
+                    println!("  Label: {}", label);
+                }
+                if let Some(share_url) = &song.share_url {
+                    println!("  {}", share_url);
+                }
+                if let Some(info) = &song.musicbrainz {
+                    println!(
+                        "  MusicBrainz recording {}, track {} of release {} ({})",
+                        info.recording_id,
+                        info.track_number.as_deref().unwrap_or("?"),
+                        info.release_id,
+                        info.release_date.as_deref().unwrap_or("undated"),
+                    );
+                }
+                for (i, candidate) in song.matches.iter().enumerate() {
+                    println!(
+                        "  {} {} @ {:.1}s: confidence {:.2}, time skew {:.5}, frequency skew {:.5}",
+                        if i == 0 { "Match" } else { "Alternative" },
+                        candidate.id,
+                        candidate.offset,
+                        candidate.confidence,
+                        candidate.time_skew.unwrap_or(0.0),
+                        candidate.frequency_skew.unwrap_or(0.0),
+                    );
+                }
+            }
+            Event::Changed(transition) => match &**transition {
+                Transition::SongStarted(song) => println!("Now playing: {} - {}", song.song_name, song.artist_name),
+                Transition::SongChanged { from, to } => println!("Track changed: {} - {} -> {} - {}", from.song_name, from.artist_name, to.song_name, to.artist_name),
+                Transition::SongEnded(song) => println!("Stopped playing: {} - {}", song.song_name, song.artist_name),
+            },
+            Event::Querying { samples } => println!("Looking up with signature from {} samples ({}s)", samples, *samples as f32 / 16_000.0),
+            Event::NoMatch => println!("No match for this song"),
+            Event::LowConfidence(song) => println!("Ignoring shaky match: {} - {} (confidence {:.2})", song.song_name, song.artist_name, song.confidence().unwrap_or_default()),
+            Event::Uncertain(song) => println!("Not sure {} - {} is still playing", song.song_name, song.artist_name),
+            Event::Predicted { track_number, next, ends_at } => {
+                let remaining = ends_at.duration_since(SystemTime::now()).unwrap_or_default().as_secs();
+                match next {
+                    Some((next_number, next_name)) => println!("  Track {}, up next: {}. {} (in {}:{:02})", track_number, next_number, next_name, remaining / 60, remaining % 60),
+                    None => println!("  Track {}, last on this side, ends in {}:{:02}", track_number, remaining / 60, remaining % 60),
+                }
+            }
+            Event::PredictedEnd => println!("Track should have ended, listening for the next one"),
+            Event::Queued => println!("Queueing signature for later..."),
+            Event::QueueLength(pending) => println!("{} signatures waiting to be sent", pending),
+            Event::LateMatch(song) => {
+                let ago = SystemTime::now().duration_since(song.timestamp).unwrap_or_default().as_secs();
+                println!("Late match: {} - {} (played {}:{:02} ago)", song.song_name, song.artist_name, ago / 60, ago % 60);
+            }
+            Event::DroppedFromQueue(reason) => println!("Dropping queued signature: {}", reason),
+            Event::Silence => println!("Input stream went silent"),
+            Event::Error(e) => println!("Error: {}", e),
+            Event::InputLost(e) => eprintln!("[INPUT] Lost audio input: {}", e),
+            Event::Throttled(retry_after) => println!("Throttled, backing off for {}s", retry_after.as_secs()),
+            Event::BudgetExhausted(retry_after) => println!("Out of requests, the next one is allowed in {}s", retry_after.as_secs()),
+            Event::BackendFailed { backend, error } => println!("[{}] {}", backend, error),
+            Event::Shutdown => {}
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use discord_sdk::wheel::UserState;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::config::{Config, DiscordConfig};
+use crate::events::Event;
+use crate::presence::{clear_presence, make_client, update_presence, Client};
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+use crate::tracker::Transition;
+
+use super::Sink;
+
+/// Shows the current track as the user's Discord activity. Discord doesn't
+/// have to be running: the activity is published whenever it connects.
+pub struct DiscordSink {
+    client: Arc<Mutex<Client>>,
+    config: Arc<DiscordConfig>,
+    /// What the activity should be showing, kept to re-publish after reconnecting.
+    current: Arc<Mutex<Option<Box<SongRecognizedMessage>>>>,
+    watcher: JoinHandle<()>,
+    /// Clears the activity once the track should have ended.
+    expiry: Option<JoinHandle<()>>,
+}
+
+impl DiscordSink {
+    pub fn new(config: &Config) -> Result<DiscordSink, String> {
+        let client = make_client(config.discord.app_id, discord_sdk::Subscriptions::ACTIVITY)?;
+        let user = client.wheel.user();
+
+        let client = Arc::new(Mutex::new(client));
+        let config = Arc::new(config.discord.clone());
+        let current = Arc::new(Mutex::new(None));
+
+        Ok(DiscordSink {
+            watcher: tokio::spawn(watch_connection(client.clone(), config.clone(), current.clone(), user)),
+            client,
+            config,
+            current,
+            expiry: None,
+        })
+    }
+
+    async fn show(&mut self, song: Box<SongRecognizedMessage>) {
+        if let Err(e) = update_presence(self.client.lock().await, &self.config, &song).await {
+            eprintln!("[DISCORD] Failed to update presence: {}", e);
+        }
+
+        if let Some(expiry) = self.expiry.take() {
+            expiry.abort();
+        }
+        if let Some(ends_at) = song.track_ends_at() {
+            self.expiry = Some(tokio::spawn(expire(self.client.clone(), self.current.clone(), ends_at)));
+        }
+
+        *self.current.lock().await = Some(song);
+    }
+}
+
+impl Sink for DiscordSink {
+    fn name(&self) -> &'static str {
+        "DISCORD"
+    }
+
+    async fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Changed(transition) => match &**transition {
+                Transition::SongStarted(song) | Transition::SongChanged { to: song, .. } => self.show(song.clone()).await,
+                Transition::SongEnded(_) => {
+                    *self.current.lock().await = None;
+                    if let Some(expiry) = self.expiry.take() {
+                        expiry.abort();
+                    }
+                    match clear_presence(self.client.lock().await).await {
+                        Ok(_) => println!("[DISCORD] Cleared activity"),
+                        Err(e) => eprintln!("[DISCORD] Failed to clear activity: {}", e),
+                    }
+                }
+            },
+            // Later matches of the same track pin down where we are in it better
+            Event::Recognized(song) => {
+                let corrected = match &*self.current.lock().await {
+                    Some(current) if current.track_key == song.track_key => {
+                        moved(current.track_started_at(), song.track_started_at()) || current.duration != song.duration
+                    }
+                    _ => false,
+                };
+                if corrected {
+                    self.show(Box::new((**song).clone())).await;
+                }
+            }
+            Event::Shutdown => {
+                self.watcher.abort();
+                if let Some(expiry) = self.expiry.take() {
+                    expiry.abort();
+                }
+                match clear_presence(self.client.lock().await).await {
+                    Ok(_) => println!("Cleared discord activity"),
+                    Err(e) => eprintln!("[DISCORD] Failed to clear discord activity: {}", e),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Logs connection changes and puts the activity back up after reconnecting.
+async fn watch_connection(
+    client: Arc<Mutex<Client>>,
+    config: Arc<DiscordConfig>,
+    current: Arc<Mutex<Option<Box<SongRecognizedMessage>>>>,
+    mut user: discord_sdk::wheel::UserSpoke,
+) {
+    println!("[DISCORD] Waiting for Discord...");
+
+    while user.0.changed().await.is_ok() {
+        let connected_as = match &*user.0.borrow() {
+            UserState::Connected(user) => Some(user.username.clone()),
+            UserState::Disconnected(err) => {
+                eprintln!("[DISCORD] Disconnected ({}), will reconnect when Discord is back", err);
+                None
+            }
+        };
+
+        let Some(username) = connected_as else {
+            continue;
+        };
+        println!("[DISCORD] Connected to Discord, local user is {}", username);
+
+        if let Some(song) = current.lock().await.as_deref().filter(|song| !ended(song)) {
+            if let Err(e) = update_presence(client.lock().await, &config, song).await {
+                eprintln!("[DISCORD] Failed to restore presence: {}", e);
+            }
+        }
+    }
+}
+
+async fn expire(client: Arc<Mutex<Client>>, current: Arc<Mutex<Option<Box<SongRecognizedMessage>>>>, ends_at: SystemTime) {
+    tokio::time::sleep(ends_at.duration_since(SystemTime::now()).unwrap_or_default()).await;
+
+    // Keep `current`: if it's still playing after all, the next match moves the end and puts it back
+    if current.lock().await.as_deref().is_some_and(ended) {
+        match clear_presence(client.lock().await).await {
+            Ok(_) => println!("[DISCORD] Track should have ended by now, cleared activity"),
+            Err(e) => eprintln!("[DISCORD] Failed to clear activity: {}", e),
+        }
+    }
+}
+
+fn ended(song: &SongRecognizedMessage) -> bool {
+    song.track_ends_at().is_some_and(|ends_at| ends_at <= SystemTime::now())
+}
+
+/// Whether the start time moved enough to be worth updating Discord for.
+fn moved(before: Option<SystemTime>, after: Option<SystemTime>) -> bool {
+    match (before, after) {
+        (Some(before), Some(after)) => {
+            let drift = after.duration_since(before).unwrap_or_else(|e| e.duration());
+            drift >= Duration::from_secs(1)
+        }
+        (before, after) => before.is_some() != after.is_some(),
+    }
+}
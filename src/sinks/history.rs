@@ -0,0 +1,37 @@
+use crate::config::Config;
+use crate::events::Event;
+use crate::history::History;
+use crate::tracker::Transition;
+
+use super::Sink;
+
+/// Logs every track played to the history file, including the ones only
+/// recognised later from the offline queue.
+pub struct HistorySink {
+    history: History,
+}
+
+impl HistorySink {
+    pub fn new(config: &Config) -> HistorySink {
+        HistorySink {
+            history: History::new(config),
+        }
+    }
+}
+
+impl Sink for HistorySink {
+    fn name(&self) -> &'static str {
+        "HISTORY"
+    }
+
+    async fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Changed(transition) => match &**transition {
+                Transition::SongStarted(song) | Transition::SongChanged { to: song, .. } => self.history.record(song),
+                Transition::SongEnded(_) => self.history.forget_last_track(),
+            },
+            Event::LateMatch(song) => self.history.record_late(song),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::events::Event;
+use crate::scrobbler::{Scrobble, Scrobbler};
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+use crate::tracker::Transition;
+
+use super::Sink;
+
+/// Tracks shorter than this aren't scrobbled.
+const MIN_LENGTH: Duration = Duration::from_secs(30);
+/// A track counts as played after half of it, or this much, whichever comes
+/// first. Tracks we don't know the length of have to get this far.
+const MAX_PLAYED: Duration = Duration::from_secs(4 * 60);
+
+/// Sends "now playing" when a track starts and scrobbles it once enough of it
+/// has played, going by where in the track we are rather than how long we've
+/// been listening, since matches can take a while to come in.
+pub struct ScrobblerSink {
+    scrobbler: Arc<Mutex<Scrobbler>>,
+    play: Option<Play>,
+}
+
+struct Play {
+    song: Box<SongRecognizedMessage>,
+    started_at: SystemTime,
+    /// When it counts as played. `None` if it's too short to ever count.
+    due: Option<SystemTime>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl ScrobblerSink {
+    pub fn new(config: &Config) -> Result<ScrobblerSink, String> {
+        let scrobbler = Arc::new(Mutex::new(Scrobbler::new(config)?));
+
+        // Send whatever was left over from last time
+        let leftovers = scrobbler.clone();
+        tokio::spawn(async move { leftovers.lock().await.flush().await });
+
+        Ok(ScrobblerSink {
+            scrobbler,
+            play: None,
+        })
+    }
+
+    async fn start(&mut self, song: Box<SongRecognizedMessage>) {
+        {
+            let mut scrobbler = self.scrobbler.lock().await;
+            match scrobbler.now_playing(&song).await {
+                Ok(_) => println!("[SCROBBLER] Now playing {} - {}", song.song_name, song.artist_name),
+                Err(e) => eprintln!("[SCROBBLER] Failed to send now playing: {}", e),
+            }
+            scrobbler.flush().await;
+        }
+
+        self.play = Some(self.schedule(song));
+    }
+
+    fn schedule(&self, song: Box<SongRecognizedMessage>) -> Play {
+        let started_at = song.track_started_at().unwrap_or(song.timestamp);
+        let due = match song.duration {
+            Some(duration) if duration < MIN_LENGTH => None,
+            Some(duration) => Some(started_at + (duration / 2).min(MAX_PLAYED)),
+            None => Some(started_at + MAX_PLAYED),
+        };
+
+        let timer = due.map(|due| {
+            let scrobbler = self.scrobbler.clone();
+            let scrobble = Scrobble::new(&song, started_at);
+            tokio::spawn(async move {
+                tokio::time::sleep(due.duration_since(SystemTime::now()).unwrap_or_default()).await;
+                println!("[SCROBBLER] Scrobbling {} - {}", scrobble.track, scrobble.artist);
+                scrobbler.lock().await.scrobble(scrobble).await;
+            })
+        });
+
+        Play {
+            song,
+            started_at,
+            due,
+            timer,
+        }
+    }
+
+    /// The play is over. If it didn't get far enough to count, it's not scrobbled.
+    fn finish(&mut self) {
+        let Some(play) = self.play.take() else {
+            return;
+        };
+
+        if play.due.is_some_and(|due| due <= SystemTime::now()) {
+            // Already scrobbled, or about to be
+            return;
+        }
+        if let Some(timer) = play.timer {
+            timer.abort();
+        }
+    }
+}
+
+impl Sink for ScrobblerSink {
+    fn name(&self) -> &'static str {
+        "SCROBBLER"
+    }
+
+    async fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Changed(transition) => match &**transition {
+                Transition::SongStarted(song) | Transition::SongChanged { to: song, .. } => {
+                    self.finish();
+                    self.start(song.clone()).await;
+                }
+                Transition::SongEnded(_) => self.finish(),
+            },
+            // Later matches of the same track pin down when it started and how long it is
+            Event::Recognized(song) => {
+                let Some(play) = &self.play else {
+                    return;
+                };
+                if play.song.track_key != song.track_key || play.due.is_some_and(|due| due <= SystemTime::now()) {
+                    return;
+                }
+
+                let started_at = song.track_started_at().unwrap_or(song.timestamp);
+                let drift = started_at.duration_since(play.started_at).unwrap_or_else(|e| e.duration());
+                if drift < Duration::from_secs(1) && play.song.duration == song.duration {
+                    return;
+                }
+
+                if let Some(timer) = self.play.take().and_then(|play| play.timer) {
+                    timer.abort();
+                }
+                self.play = Some(self.schedule(Box::new((**song).clone())));
+            }
+            Event::Shutdown => self.finish(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ScrobblerConfig;
+    use crate::testing::{message, song, temp_dir, Stub, SONG_A};
+
+    async fn sink(stub: &Stub, name: &str) -> ScrobblerSink {
+        ScrobblerSink::new(&Config {
+            data_dir: temp_dir(name),
+            scrobbler: Some(ScrobblerConfig {
+                api_key: "test-key".to_string(),
+                api_secret: "test-secret".to_string(),
+                session_key: "test-session".to_string(),
+                api_root: format!("{}/2.0/", stub.url),
+            }),
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    /// A track with no known length, that started `played` seconds ago.
+    fn started(played: f32) -> Box<SongRecognizedMessage> {
+        let mut song = message("612846392", &song(SONG_A, 0, 1, 1));
+        // The seek is where the 1 second window starts
+        song.track_seek = Some(played - 1.0);
+        Box::new(song)
+    }
+
+    fn scrobbles(stub: &Stub) -> usize {
+        stub.requests().iter().filter(|request| String::from_utf8_lossy(&request.body).contains("method=track.scrobble")).count()
+    }
+
+    async fn play(sink: &mut ScrobblerSink, song: Box<SongRecognizedMessage>) {
+        sink.handle(&Event::Changed(Arc::new(Transition::SongStarted(song.clone())))).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sink.handle(&Event::Changed(Arc::new(Transition::SongEnded(song)))).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    #[tokio::test]
+    async fn needs_four_minutes_without_a_length() {
+        let stub = Stub::start(|_| (200, "{}".to_string())).await;
+        let mut sink = sink(&stub, "scrobbler_sink_short").await;
+
+        play(&mut sink, started(60.0)).await;
+        assert_eq!(stub.requests().len(), 1, "only now playing");
+        assert_eq!(scrobbles(&stub), 0);
+
+        play(&mut sink, started(241.0)).await;
+        assert_eq!(scrobbles(&stub), 1);
+    }
+
+    #[tokio::test]
+    async fn needs_half_with_a_length() {
+        let stub = Stub::start(|_| (200, "{}".to_string())).await;
+        let mut sink = sink(&stub, "scrobbler_sink_half").await;
+
+        let mut song = started(60.0);
+        song.duration = Some(Duration::from_secs(180));
+        play(&mut sink, song).await;
+        assert_eq!(scrobbles(&stub), 0);
+
+        let mut song = started(91.0);
+        song.duration = Some(Duration::from_secs(180));
+        play(&mut sink, song).await;
+        assert_eq!(scrobbles(&stub), 1);
+
+        // Too short to ever count
+        let mut song = started(25.0);
+        song.duration = Some(Duration::from_secs(25));
+        play(&mut sink, song).await;
+        assert_eq!(scrobbles(&stub), 1);
+    }
+}
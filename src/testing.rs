@@ -0,0 +1,295 @@
+//! Fixtures shared by the tests. Audio is synthetic since we can't ship real recordings.
+
+// The stub server and data dir helpers are only for the networked parts
+#![cfg_attr(not(feature = "shazam-client"), allow(dead_code))]
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::shazam::core::thread_messages::{SongRecognizedMessage, TrackLinks};
+use crate::shazam::fingerprinting::algorithm::SignatureGenerator;
+
+const RATE: usize = 16_000;
+
+/// A "recording": `chords` of plucked notes with harmonics played in a loop,
+/// half a second each, with a little noise on top.
+pub fn song(chords: &[&[f32]], start_secs: usize, secs: usize, seed: u64) -> Vec<i16> {
+    let mut noise = Noise(seed);
+    (start_secs * RATE..(start_secs + secs) * RATE)
+        .map(|i| {
+            let t = i as f32 / RATE as f32;
+            let chord = chords[(i / (RATE / 2)) % chords.len()];
+            let since_attack = (i % (RATE / 2)) as f32 / RATE as f32;
+            let envelope = (-since_attack * 4.0).exp();
+            let note = chord
+                .iter()
+                .flat_map(|f| (1..=12).map(move |harmonic| (f * harmonic as f32, 1.0 / harmonic as f32)))
+                .map(|(f, amplitude)| amplitude * (t * f * std::f32::consts::TAU).sin())
+                .sum::<f32>()
+                / chord.len() as f32;
+            (note * envelope * 6_000.0 + noise.next() * 200.0) as i16
+        })
+        .collect()
+}
+
+/// White noise.
+pub fn noise(secs: usize, seed: u64) -> Vec<i16> {
+    let mut noise = Noise(seed);
+    (0..secs * RATE).map(|_| (noise.next() * 12_000.0) as i16).collect()
+}
+
+pub const SONG_A: &[&[f32]] = &[
+    &[261.6, 329.6, 392.0],
+    &[220.0, 261.6, 329.6],
+    &[174.6, 220.0, 261.6],
+    &[196.0, 246.9, 293.7],
+    &[261.6, 329.6, 392.0, 523.3],
+    &[293.7, 349.2, 440.0],
+    &[329.6, 392.0, 493.9],
+    &[196.0, 246.9, 293.7, 392.0],
+];
+
+pub const SONG_B: &[&[f32]] = &[
+    &[277.2, 349.2, 415.3],
+    &[233.1, 277.2, 349.2],
+    &[185.0, 233.1, 277.2],
+    &[207.7, 261.6, 311.1],
+    &[311.1, 370.0, 466.2],
+    &[370.0, 440.0, 554.4],
+];
+
+struct Noise(u64);
+
+impl Noise {
+    /// xorshift, -1..1
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+/// An empty directory to use as `data_dir`, unique to the test.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("song_id_test_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A match for `track_key` with just the basics filled in.
+pub fn message(track_key: &str, audio: &[i16]) -> SongRecognizedMessage {
+    SongRecognizedMessage {
+        artist_name: format!("Artist of {}", track_key),
+        album_name: None,
+        song_name: format!("Song {}", track_key),
+        cover_image: None,
+        local_cover: None,
+        track_seek: None,
+        matches: vec![],
+        signature: Box::new(SignatureGenerator::make_signature_from_buffer(audio)),
+        track_key: track_key.to_string(),
+        release_year: None,
+        genre: None,
+        isrc: None,
+        label: None,
+        share_url: None,
+        links: TrackLinks::default(),
+        album_id: None,
+        lyrics: None,
+        musicbrainz: None,
+        duration: None,
+        shazam_json: String::new(),
+        timestamp: SystemTime::now(),
+        extrapolated: false,
+    }
+}
+
+/// A request the stub server got.
+pub struct Request {
+    pub method: String,
+    /// Path and query string.
+    pub target: String,
+    /// Names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Decodes an `application/x-www-form-urlencoded` body.
+    #[cfg(feature = "scrobbler")]
+    pub fn form(&self) -> Vec<(String, String)> {
+        let decode = |text: &str| {
+            let text = text.replace('+', " ");
+            let mut bytes = vec![];
+            let mut rest = text.as_bytes();
+            while let Some((&byte, tail)) = rest.split_first() {
+                if byte == b'%' && tail.len() >= 2 {
+                    bytes.push(u8::from_str_radix(std::str::from_utf8(&tail[..2]).unwrap(), 16).unwrap());
+                    rest = &tail[2..];
+                } else {
+                    bytes.push(byte);
+                    rest = tail;
+                }
+            }
+            String::from_utf8(bytes).unwrap()
+        };
+
+        std::str::from_utf8(&self.body)
+            .unwrap()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode(name), decode(value))
+            })
+            .collect()
+    }
+
+    /// Splits a `multipart/form-data` body into its parts.
+    pub fn multipart(&self) -> Vec<FormPart> {
+        let content_type = self.header("content-type").unwrap();
+        let boundary = format!("--{}", content_type.split_once("boundary=").unwrap().1.trim_matches('"'));
+
+        let mut parts = vec![];
+        let mut rest = &self.body[..];
+        while let Some(start) = find(rest, boundary.as_bytes()) {
+            rest = &rest[start + boundary.len()..];
+            if rest.starts_with(b"--") {
+                break;
+            }
+            let head_end = find(rest, b"\r\n\r\n").unwrap();
+            let head = String::from_utf8_lossy(&rest[2..head_end]).into_owned();
+            let data_end = find(rest, boundary.as_bytes()).unwrap() - 2;
+
+            let disposition = head.lines().find(|line| line.to_lowercase().starts_with("content-disposition")).unwrap();
+            let param = |name: &str| {
+                let start = disposition.find(&format!("{}=\"", name))? + name.len() + 2;
+                Some(disposition[start..][..disposition[start..].find('"')?].to_string())
+            };
+            parts.push(FormPart {
+                name: param("name").unwrap(),
+                file_name: param("filename"),
+                content_type: head
+                    .lines()
+                    .find_map(|line| line.split_once(':').filter(|(name, _)| name.eq_ignore_ascii_case("content-type")))
+                    .map(|(_, value)| value.trim().to_string()),
+                data: rest[head_end + 4..data_end].to_vec(),
+            });
+        }
+        parts
+    }
+}
+
+pub struct FormPart {
+    pub name: String,
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl FormPart {
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.data).unwrap()
+    }
+}
+
+/// Value of the `name` text field among `parts`.
+pub fn field<'a>(parts: &'a [FormPart], name: &str) -> &'a str {
+    parts.iter().find(|part| part.name == name).unwrap_or_else(|| panic!("no {} field", name)).text()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// A local HTTP server answering each request with whatever `respond` returns
+/// as (status, JSON body). Keeps every request it got, in order.
+pub struct Stub {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Stub {
+    pub async fn start(respond: impl Fn(&Request) -> (u16, String) + Send + Sync + 'static) -> Stub {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let respond = Arc::new(respond);
+        let log = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let respond = respond.clone();
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let Some(request) = read_request(stream).await else {
+                        return;
+                    };
+                    let (status, body) = respond(&request.0);
+                    let response = format!(
+                        "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let mut stream = request.1;
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                    log.lock().unwrap().push(request.0);
+                });
+            }
+        });
+
+        Stub { url, requests }
+    }
+
+    /// Everything received so far.
+    pub fn requests(&self) -> std::sync::MutexGuard<'_, Vec<Request>> {
+        self.requests.lock().unwrap()
+    }
+}
+
+async fn read_request(mut stream: TcpStream) -> Option<(Request, TcpStream)> {
+    let mut data = vec![];
+    let mut buffer = [0; 4096];
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        let read = stream.read(&mut buffer).await.ok().filter(|&read| read > 0)?;
+        data.extend_from_slice(&buffer[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let length: usize = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = data.split_off(head_end + 4);
+    while body.len() < length {
+        let read = stream.read(&mut buffer).await.ok().filter(|&read| read > 0)?;
+        body.extend_from_slice(&buffer[..read]);
+    }
+
+    Some((Request { method, target, headers, body }, stream))
+}
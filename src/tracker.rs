@@ -0,0 +1,282 @@
+use crate::config::Config;
+use crate::shazam::core::thread_messages::SongRecognizedMessage;
+
+pub enum PlaybackState {
+    /// Nothing to listen to, the input is silent.
+    Idle,
+    /// There's sound, but we don't know what's playing.
+    Listening,
+    Playing(Box<SongRecognizedMessage>),
+    /// Still showing this track, but the last windows didn't agree with it.
+    Uncertain(Box<SongRecognizedMessage>),
+}
+
+/// What outputs should act on, as opposed to the individual windows.
+#[allow(clippy::enum_variant_names)]
+pub enum Transition {
+    SongStarted(Box<SongRecognizedMessage>),
+    SongEnded(Box<SongRecognizedMessage>),
+    SongChanged {
+        from: Box<SongRecognizedMessage>,
+        to: Box<SongRecognizedMessage>,
+    },
+}
+
+/// Debounces per-window results into track changes, so a single bad window
+/// (like a quiet passage) doesn't end the track and a single odd match
+/// doesn't replace it.
+pub struct Tracker {
+    state: PlaybackState,
+    /// A different track we've heard, and how many windows in a row agreed on it.
+    pending: Option<(Box<SongRecognizedMessage>, u32)>,
+    failures: u32,
+
+    confirmations: u32,
+    failures_to_clear: u32,
+    start_tolerance_secs: f32,
+}
+
+impl Tracker {
+    pub fn new(config: &Config) -> Tracker {
+        Tracker {
+            state: PlaybackState::Idle,
+            pending: None,
+            failures: 0,
+            confirmations: config.tracker.confirmations.max(1),
+            failures_to_clear: config.tracker.failures_to_clear.max(1),
+            start_tolerance_secs: config.tracker.start_tolerance_secs,
+        }
+    }
+
+    pub fn state(&self) -> &PlaybackState {
+        &self.state
+    }
+
+    /// A window was recognised, possibly by extrapolating from the last one.
+    pub fn observe_match(&mut self, song: SongRecognizedMessage) -> Option<Transition> {
+        let song = Box::new(song);
+
+        if self.current().is_some_and(|current| self.same_playback(current, &song)) {
+            self.state = PlaybackState::Playing(song);
+            self.pending = None;
+            self.failures = 0;
+            return None;
+        }
+
+        let count = match self.pending.take() {
+            Some((pending, count)) if self.same_playback(&pending, &song) => count + 1,
+            _ => 1,
+        };
+
+        if count < self.confirmations {
+            self.pending = Some((song, count));
+            self.state = match std::mem::replace(&mut self.state, PlaybackState::Listening) {
+                PlaybackState::Playing(current) | PlaybackState::Uncertain(current) => PlaybackState::Uncertain(current),
+                _ => PlaybackState::Listening,
+            };
+            return None;
+        }
+
+        self.failures = 0;
+        match std::mem::replace(&mut self.state, PlaybackState::Playing(song.clone())) {
+            PlaybackState::Playing(from) | PlaybackState::Uncertain(from) => Some(Transition::SongChanged { from, to: song }),
+            _ => Some(Transition::SongStarted(song)),
+        }
+    }
+
+    /// A window had sound but no usable match.
+    pub fn observe_failure(&mut self) -> Option<Transition> {
+        self.fail(PlaybackState::Listening)
+    }
+
+    /// A window was silent.
+    pub fn observe_silence(&mut self) -> Option<Transition> {
+        self.fail(PlaybackState::Idle)
+    }
+
+    fn fail(&mut self, cleared: PlaybackState) -> Option<Transition> {
+        self.pending = None;
+
+        match std::mem::replace(&mut self.state, PlaybackState::Listening) {
+            PlaybackState::Playing(current) | PlaybackState::Uncertain(current) => {
+                self.failures += 1;
+                if self.failures >= self.failures_to_clear {
+                    self.failures = 0;
+                    self.state = cleared;
+                    Some(Transition::SongEnded(current))
+                } else {
+                    self.state = PlaybackState::Uncertain(current);
+                    None
+                }
+            }
+            _ => {
+                self.state = cleared;
+                None
+            }
+        }
+    }
+
+    fn current(&self) -> Option<&SongRecognizedMessage> {
+        match &self.state {
+            PlaybackState::Playing(current) | PlaybackState::Uncertain(current) => Some(current),
+            _ => None,
+        }
+    }
+
+    /// Same track, and going by the seek positions, the same play of it rather
+    /// than it being started over.
+    fn same_playback(&self, a: &SongRecognizedMessage, b: &SongRecognizedMessage) -> bool {
+        if a.track_key != b.track_key {
+            return false;
+        }
+
+        match (a.track_started_at(), b.track_started_at()) {
+            (Some(a), Some(b)) => {
+                let difference = a.duration_since(b).or_else(|_| b.duration_since(a)).unwrap_or_default();
+                difference.as_secs_f32() <= self.start_tolerance_secs
+            }
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::config::TrackerConfig;
+    use crate::testing::{message, song, SONG_A};
+
+    fn tracker() -> Tracker {
+        Tracker::new(&Config {
+            tracker: TrackerConfig {
+                confirmations: 3,
+                failures_to_clear: 2,
+                start_tolerance_secs: 10.0,
+            },
+            ..Config::default()
+        })
+    }
+
+    /// A match for `track_key` heard `at` seconds into the test, that puts the
+    /// start of the track at `started_at`.
+    fn heard(track_key: &str, started_at: f32, at: f32) -> SongRecognizedMessage {
+        let mut song = message(track_key, &song(SONG_A, 0, 1, 1));
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        song.timestamp = t0 + Duration::from_secs_f32(at);
+        // The seek is where the window starts
+        song.track_seek = Some(at - started_at - 1.0);
+        song
+    }
+
+    fn state(tracker: &Tracker) -> String {
+        match tracker.state() {
+            PlaybackState::Idle => "idle".to_string(),
+            PlaybackState::Listening => "listening".to_string(),
+            PlaybackState::Playing(song) => format!("playing {}", song.track_key),
+            PlaybackState::Uncertain(song) => format!("uncertain {}", song.track_key),
+        }
+    }
+
+    fn describe(transition: Option<Transition>) -> String {
+        match transition {
+            None => "-".to_string(),
+            Some(Transition::SongStarted(song)) => format!("started {}", song.track_key),
+            Some(Transition::SongEnded(song)) => format!("ended {}", song.track_key),
+            Some(Transition::SongChanged { from, to }) => format!("changed {} to {}", from.track_key, to.track_key),
+        }
+    }
+
+    fn playing(track_key: &str) -> Tracker {
+        let mut tracker = tracker();
+        for i in 0..3 {
+            tracker.observe_match(heard(track_key, 0.0, i as f32 * 12.0));
+        }
+        assert_eq!(state(&tracker), format!("playing {}", track_key));
+        tracker
+    }
+
+    #[test]
+    fn switches_after_enough_confirmations() {
+        let mut tracker = tracker();
+
+        assert_eq!(describe(tracker.observe_match(heard("a", 0.0, 12.0))), "-");
+        assert_eq!(describe(tracker.observe_match(heard("a", 0.0, 24.0))), "-");
+        assert_eq!(state(&tracker), "listening");
+        assert_eq!(describe(tracker.observe_match(heard("a", 0.0, 36.0))), "started a");
+        assert_eq!(state(&tracker), "playing a");
+
+        assert_eq!(describe(tracker.observe_match(heard("b", 40.0, 48.0))), "-");
+        assert_eq!(describe(tracker.observe_match(heard("b", 40.0, 60.0))), "-");
+        assert_eq!(state(&tracker), "uncertain a");
+        assert_eq!(describe(tracker.observe_match(heard("b", 40.0, 72.0))), "changed a to b");
+        assert_eq!(state(&tracker), "playing b");
+    }
+
+    #[test]
+    fn confirmations_have_to_be_in_a_row() {
+        let mut tracker = playing("a");
+
+        assert_eq!(describe(tracker.observe_match(heard("b", 40.0, 48.0))), "-");
+        assert_eq!(describe(tracker.observe_match(heard("c", 40.0, 60.0))), "-");
+        assert_eq!(describe(tracker.observe_match(heard("b", 40.0, 72.0))), "-");
+        assert_eq!(describe(tracker.observe_failure()), "-");
+        assert_eq!(describe(tracker.observe_match(heard("b", 40.0, 96.0))), "-");
+        assert_eq!(state(&tracker), "uncertain a");
+    }
+
+    #[test]
+    fn ends_after_enough_failures() {
+        let mut tracker = playing("a");
+
+        assert_eq!(describe(tracker.observe_failure()), "-");
+        assert_eq!(state(&tracker), "uncertain a");
+        assert_eq!(describe(tracker.observe_silence()), "ended a");
+        assert_eq!(state(&tracker), "idle");
+        assert_eq!(describe(tracker.observe_silence()), "-");
+
+        let mut tracker = playing("a");
+        tracker.observe_silence();
+        assert_eq!(describe(tracker.observe_failure()), "ended a");
+        assert_eq!(state(&tracker), "listening");
+    }
+
+    #[test]
+    fn recovers_from_uncertain() {
+        let mut tracker = playing("a");
+
+        // A match in between resets the failure count
+        assert_eq!(describe(tracker.observe_failure()), "-");
+        assert_eq!(describe(tracker.observe_match(heard("a", 0.0, 48.0))), "-");
+        assert_eq!(state(&tracker), "playing a");
+        assert_eq!(describe(tracker.observe_failure()), "-");
+        assert_eq!(state(&tracker), "uncertain a");
+
+        // So does a stray match of something else being outvoted
+        assert_eq!(describe(tracker.observe_match(heard("a", 0.0, 72.0))), "-");
+        assert_eq!(describe(tracker.observe_match(heard("b", 80.0, 84.0))), "-");
+        assert_eq!(describe(tracker.observe_match(heard("b", 80.0, 96.0))), "-");
+        assert_eq!(describe(tracker.observe_match(heard("a", 0.0, 108.0))), "-");
+        assert_eq!(state(&tracker), "playing a");
+        assert_eq!(describe(tracker.observe_match(heard("b", 80.0, 120.0))), "-");
+        assert_eq!(state(&tracker), "uncertain a");
+    }
+
+    #[test]
+    fn restarting_the_same_track_is_a_new_play() {
+        let mut tracker = playing("a");
+
+        // Matches drift a little, that's still the same play
+        assert_eq!(describe(tracker.observe_match(heard("a", 8.0, 48.0))), "-");
+        assert_eq!(state(&tracker), "playing a");
+
+        // But starting over is a change, once confirmed like any other
+        assert_eq!(describe(tracker.observe_match(heard("a", 55.0, 60.0))), "-");
+        assert_eq!(state(&tracker), "uncertain a");
+        assert_eq!(describe(tracker.observe_match(heard("a", 55.0, 72.0))), "-");
+        assert_eq!(describe(tracker.observe_match(heard("a", 55.0, 84.0))), "changed a to a");
+        assert_eq!(state(&tracker), "playing a");
+        assert_eq!(describe(tracker.observe_match(heard("a", 55.0, 96.0))), "-");
+    }
+}
@@ -0,0 +1,58 @@
+#[cfg(feature = "ffmpeg")]
+pub mod ffmpeg_wrapper;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Tells apart the temporary files of writes running at the same time.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Writes to a temporary file next to `path` and renames it over the
+/// original, so a crash halfway through leaves the old contents intact.
+/// The temporary name is unique to the process and the write, so processes
+/// sharing a `data_dir` can't trample each other's. Creates the parent
+/// directory if needed.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(".{}.{}.tmp", std::process::id(), TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let temp_path = path.with_file_name(temp_name);
+
+    let res = fs::write(&temp_path, contents).and_then(|_| fs::rename(&temp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    res
+}
+
+/// Reads a JSON file saved with [`save_json`]. Falls back to the default if it
+/// doesn't exist yet, and warns if it's there but unreadable.
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path, tag: &str) -> T {
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+            eprintln!("[{}] Ignoring unreadable {}: {}", tag, path.display(), e);
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
+
+/// Saves `value` as JSON with [`write_atomic`]. State files are only a
+/// convenience, so failures are logged rather than returned.
+pub fn save_json<T: Serialize + ?Sized>(path: &Path, value: &T, tag: &str) {
+    let res = serde_json::to_string(value)
+        .map_err(|e| e.to_string())
+        .and_then(|json| write_atomic(path, json.as_bytes()).map_err(|e| e.to_string()));
+
+    if let Err(e) = res {
+        eprintln!("[{}] Failed to save {}: {}", tag, path.display(), e);
+    }
+}
@@ -0,0 +1,193 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+/// Name of the binary looked up in `PATH`, unless `$FFMPEG` says otherwise.
+const FFMPEG_BINARY: &str = "ffmpeg";
+
+/// Samples handed over at a time: one second of audio.
+const CHUNK_SAMPLES: usize = 16_000;
+
+#[derive(Debug)]
+pub enum FfmpegError {
+    /// ffmpeg isn't installed, or not in `PATH`.
+    NotFound,
+    Io(io::Error),
+    /// ffmpeg gave up, usually because the file is unreadable or has no audio
+    /// stream it can decode. Holds what it printed.
+    Failed { status: ExitStatus, message: String },
+    /// Decoding worked but there wasn't enough audio to fingerprint.
+    NoAudio,
+}
+
+impl fmt::Display for FfmpegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FfmpegError::NotFound => write!(f, "ffmpeg not found, install it or point $FFMPEG at it"),
+            FfmpegError::Io(e) => write!(f, "Failed to talk to ffmpeg: {}", e),
+            FfmpegError::Failed { status, message } if message.is_empty() => write!(f, "ffmpeg failed ({})", status),
+            FfmpegError::Failed { status, message } => write!(f, "ffmpeg failed ({}): {}", status, message),
+            FfmpegError::NoAudio => write!(f, "No audio to fingerprint"),
+        }
+    }
+}
+
+impl Error for FfmpegError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FfmpegError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FfmpegError {
+    fn from(e: io::Error) -> Self {
+        FfmpegError::Io(e)
+    }
+}
+
+/// Runs ffmpeg on `path` and passes the first audio stream to `on_samples` as
+/// 16 kHz mono samples, a chunk at a time as it's decoded, so long files never
+/// have to fit in memory.
+pub fn decode_with_ffmpeg(path: &Path, mut on_samples: impl FnMut(&[i16])) -> Result<(), FfmpegError> {
+    let binary = std::env::var_os("FFMPEG").unwrap_or(FFMPEG_BINARY.into());
+
+    let mut child = Command::new(binary)
+        .args(["-nostdin", "-hide_banner", "-loglevel", "error", "-i"])
+        .arg(path)
+        .args(["-map", "0:a:0", "-vn", "-ac", "1", "-ar", "16000", "-f", "s16le", "-acodec", "pcm_s16le", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => FfmpegError::NotFound,
+            _ => FfmpegError::Io(e),
+        })?;
+
+    // Drained on the side so ffmpeg can't block on a full stderr pipe
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_thread = thread::spawn(move || {
+        let mut message = String::new();
+        let _ = stderr.read_to_string(&mut message);
+        message
+    });
+
+    let mut stdout = child.stdout.take().unwrap();
+    let mut bytes = vec![0u8; CHUNK_SAMPLES * 2];
+    let mut filled = 0;
+    let mut samples = Vec::with_capacity(CHUNK_SAMPLES);
+
+    loop {
+        let read = match stdout.read(&mut bytes[filled..]) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                let _ = child.kill();
+                return Err(e.into());
+            }
+        };
+        filled += read;
+
+        if filled == bytes.len() || (read == 0 && filled > 1) {
+            let whole = filled - filled % 2;
+            samples.clear();
+            samples.extend(bytes[..whole].chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])));
+            on_samples(&samples);
+
+            bytes.copy_within(whole..filled, 0);
+            filled -= whole;
+        }
+
+        if read == 0 {
+            break;
+        }
+    }
+
+    let status = child.wait()?;
+    let message = stderr_thread.join().unwrap_or_default();
+
+    if !status.success() {
+        return Err(FfmpegError::Failed { status, message: message.trim().to_string() });
+    }
+
+    Ok(())
+}
+
+// The stand-in ffmpegs are shell scripts
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::temp_dir;
+
+    /// `$FFMPEG` is shared by the whole process.
+    static FFMPEG: Mutex<()> = Mutex::new(());
+
+    fn fake_ffmpeg(dir: &Path, script: &str) -> PathBuf {
+        let path = dir.join("ffmpeg");
+        fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn decode_with(binary: &Path) -> Result<Vec<Vec<i16>>, FfmpegError> {
+        let _guard = FFMPEG.lock().unwrap_or_else(|e| e.into_inner());
+        std::env::set_var("FFMPEG", binary);
+
+        let mut chunks = vec![];
+        let res = decode_with_ffmpeg(Path::new("input.mp3"), |samples| chunks.push(samples.to_vec()));
+        std::env::remove_var("FFMPEG");
+        res.map(|_| chunks)
+    }
+
+    #[test]
+    fn reports_a_missing_ffmpeg() {
+        let dir = temp_dir("ffmpeg_missing");
+        assert!(matches!(decode_with(&dir.join("no-such-ffmpeg")), Err(FfmpegError::NotFound)));
+    }
+
+    #[test]
+    fn reports_what_ffmpeg_said_when_it_fails() {
+        let dir = temp_dir("ffmpeg_failed");
+        let ffmpeg = fake_ffmpeg(&dir, "echo 'input.mp3: Invalid data found when processing input' >&2\nexit 1\n");
+
+        match decode_with(&ffmpeg) {
+            Err(FfmpegError::Failed { status, message }) => {
+                assert_eq!(status.code(), Some(1));
+                assert_eq!(message, "input.mp3: Invalid data found when processing input");
+            }
+            other => panic!("expected a failure, got {:?}", other.map(|chunks| chunks.len())),
+        }
+    }
+
+    #[test]
+    fn puts_samples_split_across_reads_back_together() {
+        let dir = temp_dir("ffmpeg_odd_chunks");
+
+        // 1.25 chunks' worth, plus a stray byte at the end that isn't a whole sample
+        let samples: Vec<i16> = (0..CHUNK_SAMPLES as i32 * 5 / 4).map(|i| (i * 7 - 30_000) as i16).collect();
+        let mut raw: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        raw.push(0x7f);
+        fs::write(dir.join("samples.raw"), &raw).unwrap();
+
+        // Odd-sized writes, so samples get cut in half between reads
+        let ffmpeg = fake_ffmpeg(&dir, &format!(
+            "offset=1\nwhile [ $offset -le {} ]; do\n  tail -c +$offset '{}' | head -c 4999\n  sleep 0.01\n  offset=$((offset + 4999))\ndone\n",
+            raw.len(),
+            dir.join("samples.raw").display(),
+        ));
+
+        let chunks = decode_with(&ffmpeg).unwrap();
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [CHUNK_SAMPLES, CHUNK_SAMPLES / 4]);
+        assert_eq!(chunks.concat(), samples);
+    }
+}